@@ -9,6 +9,9 @@ fn main() {
         .with_crate(&crate_dir)
         .with_parse_deps(true)
         .with_parse_include(&["bochscpu"])
+        // only passed as uint32_t, so nothing refers to these by name
+        .include_item("bochscpu_mem_missing_t")
+        .include_item("bochscpu_watch_space_t")
         .with_header("#pragma once")
         .generate()
         .expect("Unable to generate bindings")
@@ -18,6 +21,8 @@ fn main() {
         .with_crate(&crate_dir)
         .with_parse_deps(true)
         .with_parse_include(&["bochscpu"])
+        .include_item("bochscpu_mem_missing_t")
+        .include_item("bochscpu_watch_space_t")
         .with_language(cbindgen::Language::C)
         .with_header("#pragma once")
         .generate()
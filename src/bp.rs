@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use bochscpu::cpu::Cpu;
+use bochscpu::hook::*;
+
+use crate::cpu::bochscpu_cpu_t;
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or};
+use crate::hook::{self, BOCHSCPU_HOOK_ACTION_CONTINUE};
+
+/// Breakpoint hit callback
+///
+/// Called with the id of the cpu, the id of the breakpoint and its GVA, before
+/// the instruction executes. Returns one of the `BOCHSCPU_HOOK_ACTION_*`
+/// constants.
+#[allow(non_camel_case_types)]
+pub type bochscpu_bp_cb_t =
+    Option<extern "C" fn(ctx: *mut c_void, cpu: u32, bp: u64, gva: u64) -> u32>;
+
+struct Bp {
+    cpu: u32,
+    gva: u64,
+    ctx: usize,
+    cb: bochscpu_bp_cb_t,
+    // shared with running cpus, so toggling or counting does not need a new
+    // index
+    enabled: AtomicBool,
+    hits: AtomicU64,
+}
+
+/// Ids and breakpoints at one cpu and gva
+type At = Vec<(u64, Arc<Bp>)>;
+
+/// Immutable view of the breakpoints handed to running cpus, sorted by cpu
+/// and gva
+type Index = Vec<((u32, u64), At)>;
+
+struct Bps {
+    bps: BTreeMap<u64, Arc<Bp>>,
+    next_id: u64,
+    index: Option<Arc<Index>>,
+}
+
+impl Bps {
+    /// Drop the index after a change, it is rebuilt on next use
+    fn changed(&mut self) {
+        self.index = None;
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+
+    fn index(&mut self) -> Arc<Index> {
+        if let Some(index) = &self.index {
+            return index.clone();
+        }
+
+        let mut at: BTreeMap<(u32, u64), At> = BTreeMap::new();
+        for (&id, bp) in &self.bps {
+            at.entry((bp.cpu, bp.gva))
+                .or_default()
+                .push((id, bp.clone()));
+        }
+
+        let index: Arc<Index> = Arc::new(at.into_iter().collect());
+        self.index = Some(index.clone());
+        index
+    }
+}
+
+static BPS: Mutex<Bps> = Mutex::new(Bps {
+    bps: BTreeMap::new(),
+    next_id: 0,
+    index: None,
+});
+
+fn bps() -> MutexGuard<'static, Bps> {
+    BPS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// bumped whenever a breakpoint is added or removed, so running cpus only take
+// the lock to pick up a new index
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn enabled() -> bool {
+    !bps().bps.is_empty()
+}
+
+/// Evaluates breakpoints before each instruction
+#[derive(Default)]
+pub(crate) struct BpHooks {
+    // copy of the index as of `generation`
+    index: Arc<Index>,
+    generation: u64,
+}
+
+impl BpHooks {
+    fn refresh(&mut self) {
+        let generation = GENERATION.load(Ordering::Acquire);
+
+        if generation != self.generation {
+            self.index = bps().index();
+            self.generation = generation;
+        }
+    }
+
+    /// Call the callbacks of the enabled breakpoints at `rip`
+    ///
+    /// Returns the first action other than `BOCHSCPU_HOOK_ACTION_CONTINUE`.
+    fn hit(&mut self, id: u32, rip: u64) -> u32 {
+        self.refresh();
+
+        let Ok(idx) = self.index.binary_search_by_key(&(id, rip), |(at, _)| *at) else {
+            return BOCHSCPU_HOOK_ACTION_CONTINUE;
+        };
+
+        let mut action = BOCHSCPU_HOOK_ACTION_CONTINUE;
+        for (bp, e) in &self.index[idx].1 {
+            if !e.enabled.load(Ordering::Relaxed) {
+                continue;
+            }
+            e.hits.fetch_add(1, Ordering::Relaxed);
+
+            if let Some(cb) = e.cb {
+                let a = cb(e.ctx as _, id, *bp, e.gva);
+
+                if action == BOCHSCPU_HOOK_ACTION_CONTINUE {
+                    action = a;
+                }
+            }
+        }
+
+        action
+    }
+}
+
+impl Hooks for BpHooks {
+    fn before_execution(&mut self, id: u32, _ins: *mut c_void) {
+        let rip = unsafe { Cpu::from(id).rip() };
+
+        // acting may bail out of the instruction without returning here, so
+        // nothing may be left to drop by then
+        let action = self.hit(id, rip);
+
+        hook::act(id, action);
+    }
+}
+
+/// Add a breakpoint
+///
+/// `cb` is called with `ctx` each time the cpu is about to execute the
+/// instruction at `gva`, and may be NULL to only count hits. Breakpoints are
+/// checked inside the library, so unlike a `before_execution` hook they do
+/// not cross the FFI boundary on every instruction. New breakpoints are
+/// enabled.
+///
+/// When several breakpoints share a GVA every callback is called, then the
+/// first action other than `BOCHSCPU_HOOK_ACTION_CONTINUE` is carried out.
+///
+/// The id of the new breakpoint is written to `id`.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`,
+/// and `id` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_bp_add(
+    p: bochscpu_cpu_t,
+    gva: u64,
+    ctx: *mut c_void,
+    cb: bochscpu_bp_cb_t,
+    id: *mut u64,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+        let cpu = c.id();
+
+        let mut b = bps();
+        let next = b.next_id;
+        b.next_id += 1;
+        b.bps.insert(
+            next,
+            Arc::new(Bp {
+                cpu,
+                gva,
+                ctx: ctx as usize,
+                cb,
+                enabled: AtomicBool::new(true),
+                hits: AtomicU64::new(0),
+            }),
+        );
+        b.changed();
+
+        *id = next;
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Remove a breakpoint
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if no breakpoint has the id `id`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_bp_remove(id: u64) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || {
+        let mut b = bps();
+
+        if b.bps.remove(&id).is_none() {
+            return fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("no breakpoint with id {}", id),
+            );
+        }
+        b.changed();
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Enable or disable a breakpoint
+///
+/// Disabled breakpoints do not call their callback or count hits.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if no breakpoint has the id `id`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_bp_enable(id: u64, enable: bool) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || {
+        match bps().bps.get(&id) {
+            Some(e) => {
+                e.enabled.store(enable, Ordering::Relaxed);
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            None => fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("no breakpoint with id {}", id),
+            ),
+        }
+    })
+}
+
+/// Get the number of times a breakpoint was hit while enabled
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if no breakpoint has the id `id`.
+///
+/// # Safety
+///
+/// `hits` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_bp_hits(id: u64, hits: *mut u64) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || {
+        match bps().bps.get(&id) {
+            Some(e) => {
+                unsafe { *hits = e.hits.load(Ordering::Relaxed) };
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            None => fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("no breakpoint with id {}", id),
+            ),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use bochscpu::opcode::instr_ilen;
+
+    use super::*;
+    use crate::cpu::{bochscpu_cpu_delete, bochscpu_cpu_new};
+    use crate::hook::{
+        ActionHooks, BOCHSCPU_HOOK_ACTION_SKIP, BOCHSCPU_HOOK_ACTION_STOP, take_restart,
+    };
+    use crate::testutil::lock;
+
+    struct Log {
+        calls: Vec<u64>,
+        action: u32,
+    }
+
+    extern "C" fn cb(ctx: *mut c_void, _cpu: u32, bp: u64, _gva: u64) -> u32 {
+        let log = unsafe { &mut *(ctx as *mut Log) };
+        log.calls.push(bp);
+
+        log.action
+    }
+
+    fn hits(bp: u64) -> u64 {
+        let mut n = 0;
+        let status = unsafe { bochscpu_bp_hits(bp, &mut n) };
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+
+        n
+    }
+
+    #[test]
+    fn add_remove_hit() {
+        let _l = lock();
+        let id = 0x308;
+        let mut log = Log {
+            calls: Vec::new(),
+            action: BOCHSCPU_HOOK_ACTION_CONTINUE,
+        };
+
+        unsafe {
+            let p = bochscpu_cpu_new(id);
+            let ctx = &mut log as *mut Log as *mut c_void;
+            let (mut a, mut b, mut c) = (0, 0, 0);
+
+            bochscpu_bp_add(p, 0x1000, ctx, Some(cb), &mut a);
+            bochscpu_bp_add(p, 0x1000, ptr::null_mut(), None, &mut b);
+            bochscpu_bp_add(p, 0x2000, ctx, Some(cb), &mut c);
+            assert!(enabled());
+
+            Cpu::from(id).set_rip(0x1000);
+            let mut hooks = BpHooks::default();
+            hooks.before_execution(id, ptr::null_mut());
+            assert_eq!(log.calls, [a]);
+            assert_eq!((hits(a), hits(b), hits(c)), (1, 1, 0));
+            assert_eq!(take_restart(), None);
+
+            // disabled breakpoints neither call back nor count
+            bochscpu_bp_enable(a, false);
+            hooks.before_execution(id, ptr::null_mut());
+            assert_eq!(log.calls, [a]);
+            assert_eq!((hits(a), hits(b)), (1, 2));
+            bochscpu_bp_enable(a, true);
+
+            // the callback's action is carried out once it returns
+            let ins = 0x10 as *mut c_void;
+            log.action = BOCHSCPU_HOOK_ACTION_SKIP;
+            ActionHooks.before_execution(id, ins);
+            hooks.before_execution(id, ins);
+            assert_eq!(take_restart(), Some(0x1000 + instr_ilen(ins) as u64));
+
+            log.action = BOCHSCPU_HOOK_ACTION_STOP;
+            hooks.before_execution(id, ins);
+            assert_eq!(take_restart(), None);
+            assert_eq!(log.calls, [a, a, a]);
+
+            // removed breakpoints are gone from running cpus too
+            assert_eq!(bochscpu_bp_remove(a), bochscpu_status_t::BOCHSCPU_STATUS_OK);
+            assert_eq!(
+                bochscpu_bp_remove(a),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+            hooks.before_execution(id, ins);
+            assert_eq!(log.calls.len(), 3);
+            assert_eq!(hits(b), 5);
+
+            bochscpu_bp_remove(b);
+            bochscpu_bp_remove(c);
+            assert!(!enabled());
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+}
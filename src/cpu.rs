@@ -1,10 +1,22 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::c_void;
 use std::mem::{self, ManuallyDrop};
 use std::ptr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 use bochscpu::cpu::*;
-
-use crate::hook::bochscpu_hooks_t;
+use bochscpu::hook::{Hooks, MemAccess, MemType};
+use bochscpu::{Address, PhyAddress};
+
+use crate::bp::{self, BpHooks};
+use crate::dirty::{self, DirtyHooks};
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard, guard_or};
+use crate::hook::{self, ActionHooks, HookDispatch, bochscpu_hooks_t};
+use crate::mem::{MissingHooks, finish_run, needs_hooks};
+use crate::mmio::{self, MmioHooks};
+use crate::paging::{self, bochscpu_mem_access_t};
+use crate::watch::{self, ExecWatchHooks, WatchHooks};
 
 #[allow(non_camel_case_types)]
 pub type bochscpu_cpu_t = *mut c_void;
@@ -16,6 +28,50 @@ pub type bochscpu_cpu_seg_t = Seg;
 pub type bochscpu_cpu_global_seg_t = GlobalSeg;
 #[allow(non_camel_case_types)]
 pub type bochscpu_cpu_zmm_t = Zmm;
+/// 80-bit x87 floating point value, split into its 64-bit significand and
+/// 16-bit sign and exponent
+#[allow(non_camel_case_types)]
+pub type bochscpu_cpu_float80_t = Float80;
+
+struct CpuRegistry {
+    // every handle given out by bochscpu_cpu_new/bochscpu_cpu_from, and its id
+    handles: BTreeMap<usize, u32>,
+    // ids of cpus which have not been deleted
+    live: BTreeSet<u32>,
+}
+
+static CPUS: Mutex<CpuRegistry> = Mutex::new(CpuRegistry {
+    handles: BTreeMap::new(),
+    live: BTreeSet::new(),
+});
+
+fn cpus() -> MutexGuard<'static, CpuRegistry> {
+    CPUS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn register_cpu(p: bochscpu_cpu_t, id: u32) -> bochscpu_cpu_t {
+    let mut cpus = cpus();
+
+    cpus.handles.insert(p as usize, id);
+    cpus.live.insert(id);
+
+    p
+}
+
+thread_local! {
+    // id of the cpu bochscpu_cpu_run() is emulating on this thread
+    static RUNNING: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Id of the cpu being run on this thread, if any
+pub(crate) fn running() -> Option<u32> {
+    RUNNING.get()
+}
+
+/// Ids of every cpu which has been created and not deleted
+pub(crate) fn live_cpus() -> Vec<u32> {
+    cpus().live.iter().copied().collect()
+}
 
 /// Create a new Cpu
 ///
@@ -23,10 +79,10 @@ pub type bochscpu_cpu_zmm_t = Zmm;
 /// ignored.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_new(id: u32) -> bochscpu_cpu_t {
-    unsafe {
+    guard_or(ptr::null_mut(), || unsafe {
         let c = Box::new(Cpu::new(id));
-        Box::into_raw(c) as _
-    }
+        register_cpu(Box::into_raw(c) as _, id)
+    })
 }
 
 /// Create a new Cpu
@@ -34,689 +90,2077 @@ pub unsafe extern "C" fn bochscpu_cpu_new(id: u32) -> bochscpu_cpu_t {
 /// Instantiate an already existing cpu with the specified id.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_from(id: u32) -> bochscpu_cpu_t {
-    let c = Box::new(Cpu::from(id));
-    Box::into_raw(c) as _
+    guard_or(ptr::null_mut(), || {
+        let c = Box::new(Cpu::from(id));
+        register_cpu(Box::into_raw(c) as _, id)
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_forget(p: bochscpu_cpu_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_forget(p: bochscpu_cpu_t) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: Box<Cpu> = Box::from_raw(p as _);
 
+        cpus().handles.remove(&(p as usize));
+
         mem::drop(c);
-    }
+    })
 }
 
 /// Delete a cpu
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_delete(p: bochscpu_cpu_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_delete(p: bochscpu_cpu_t) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: Box<Cpu> = Box::from_raw(p as _);
 
+        let mut cpus = cpus();
+        if let Some(id) = cpus.handles.remove(&(p as usize)) {
+            cpus.live.remove(&id);
+            cpus.handles.retain(|_, v| *v != id);
+        }
+        drop(cpus);
+
         c.delete();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_mode(p: bochscpu_cpu_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_mode(p: bochscpu_cpu_t) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_mode();
+    })
+}
+
+/// The library's own hooks, registered with the core as a single object
+///
+/// The core has no per-event registration and calls every registered object
+/// for every event it reports. Bundling the features keeps that to one call,
+/// which is only forwarded for the few events the features use.
+struct LibHooks<'a> {
+    action: Option<&'a mut ActionHooks>,
+    missing: Option<&'a mut MissingHooks>,
+    bp: Option<&'a mut BpHooks>,
+    dirty: Option<&'a mut DirtyHooks>,
+    mmio: Option<&'a mut MmioHooks>,
+    watch: Option<&'a mut WatchHooks>,
+    exec_watch: Option<&'a mut ExecWatchHooks>,
+}
+
+impl LibHooks<'_> {
+    fn is_empty(&self) -> bool {
+        self.action.is_none()
+            && self.missing.is_none()
+            && self.bp.is_none()
+            && self.dirty.is_none()
+            && self.mmio.is_none()
+            && self.watch.is_none()
+            && self.exec_watch.is_none()
+    }
+}
+
+impl Hooks for LibHooks<'_> {
+    fn before_execution(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(h) = self.action.as_deref_mut() {
+            h.before_execution(id, ins);
+        }
+        if let Some(h) = self.missing.as_deref_mut() {
+            h.before_execution(id, ins);
+        }
+        if let Some(h) = self.bp.as_deref_mut() {
+            h.before_execution(id, ins);
+        }
+        if let Some(h) = self.mmio.as_deref_mut() {
+            h.before_execution(id, ins);
+        }
+        if let Some(h) = self.exec_watch.as_deref_mut() {
+            h.before_execution(id, ins);
+        }
+    }
+
+    fn after_execution(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(h) = self.missing.as_deref_mut() {
+            h.after_execution(id, ins);
+        }
+        if let Some(h) = self.mmio.as_deref_mut() {
+            h.after_execution(id, ins);
+        }
+    }
+
+    fn repeat_iteration(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(h) = self.missing.as_deref_mut() {
+            h.repeat_iteration(id, ins);
+        }
+        if let Some(h) = self.mmio.as_deref_mut() {
+            h.repeat_iteration(id, ins);
+        }
+    }
+
+    fn lin_access(
+        &mut self,
+        id: u32,
+        vaddr: Address,
+        paddr: Address,
+        len: usize,
+        memty: MemType,
+        rw: MemAccess,
+    ) {
+        if let Some(h) = self.missing.as_deref_mut() {
+            h.lin_access(id, vaddr, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.dirty.as_deref_mut() {
+            h.lin_access(id, vaddr, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.mmio.as_deref_mut() {
+            h.lin_access(id, vaddr, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.watch.as_deref_mut() {
+            h.lin_access(id, vaddr, paddr, len, memty, rw);
+        }
+    }
+
+    fn phy_access(
+        &mut self,
+        id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        memty: MemType,
+        rw: MemAccess,
+    ) {
+        if let Some(h) = self.missing.as_deref_mut() {
+            h.phy_access(id, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.dirty.as_deref_mut() {
+            h.phy_access(id, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.mmio.as_deref_mut() {
+            h.phy_access(id, paddr, len, memty, rw);
+        }
+        if let Some(h) = self.watch.as_deref_mut() {
+            h.phy_access(id, paddr, len, memty, rw);
+        }
     }
 }
 
 /// Start emulation
 ///
 /// To hook emulation, pass in a NULL terminated list of one or more pointers to
-/// bochscpu_hooks_t structs.
+/// bochscpu_hooks_t structs. The structs are copied before emulation starts,
+/// so changes made to them while the cpu is running have no effect.
+///
+/// When a `*_action` hook skips or redirects, the current instruction is
+/// abandoned and emulation is restarted at the new RIP before returning.
+///
+/// Hooks must not panic or unwind: the emulator's C++ frames sit between them
+/// and this function, so a panic inside a hook aborts the process instead of
+/// being reported as `BOCHSCPU_STATUS_PANIC`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if a hooks struct has an invalid `struct_size` or
+/// `version`, in which case the cpu is not run. Also `BOCHSCPU_STATUS_ERROR`
+/// if a missing page handler aborted, see `bochscpu_mem_missing_page_ctx()`.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_run(p: bochscpu_cpu_t, h: *mut *mut bochscpu_hooks_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_run(
+    p: bochscpu_cpu_t,
+    h: *mut *mut bochscpu_hooks_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
-        let mut prep = c.prepare();
+        let mut hooks = Vec::new();
 
-        if h != ptr::null_mut() {
+        if !h.is_null() {
             let mut ii = h;
 
             loop {
-                if *ii == ptr::null_mut() {
+                if (*ii).is_null() {
                     break;
                 }
 
-                prep = prep.register(&mut **ii);
+                match bochscpu_hooks_t::from_raw(*ii) {
+                    Ok(hook) => hooks.push(hook),
+                    Err((kind, msg)) => return fail(kind, &msg),
+                }
 
                 ii = ii.add(1);
             }
         }
 
-        prep.run();
-    }
+        let mut hooks = HookDispatch::new(hooks);
+        let actions = hooks.has_actions();
+
+        // a run which returned early may have left a restart pending
+        hook::reset_run_state();
+
+        let mut action_hooks = ActionHooks;
+        let mut missing_hooks = MissingHooks::default();
+        let mut bp_hooks = BpHooks::default();
+        let mut dirty_hooks = DirtyHooks::default();
+        let mut mmio_hooks = MmioHooks::default();
+        let mut watch_hooks = WatchHooks::default();
+        let mut exec_watch_hooks = ExecWatchHooks::default();
+
+        loop {
+            let mut prep = c.prepare();
+
+            let bps = bp::enabled();
+            let mut lib = LibHooks {
+                action: (actions || bps).then_some(&mut action_hooks),
+                missing: needs_hooks().then_some(&mut missing_hooks),
+                bp: bps.then_some(&mut bp_hooks),
+                dirty: dirty::enabled().then_some(&mut dirty_hooks),
+                mmio: mmio::enabled().then_some(&mut mmio_hooks),
+                watch: watch::enabled().then_some(&mut watch_hooks),
+                exec_watch: watch::exec_enabled().then_some(&mut exec_watch_hooks),
+            };
+
+            if !lib.is_empty() {
+                prep = prep.register(&mut lib);
+            }
+
+            // the core calls a registered object for every event, so leave
+            // it out when no struct has a hook
+            if !hooks.is_empty() {
+                prep = prep.register(&mut hooks);
+            }
+
+            let outer = RUNNING.replace(Some(c.id()));
+            prep.run();
+            RUNNING.set(outer);
+
+            mmio_hooks.flush();
+            missing_hooks.flush();
+            dirty_hooks.flush();
+
+            if finish_run() {
+                return bochscpu_status_t::BOCHSCPU_STATUS_ERROR;
+            }
+
+            // a hook bailed out of an instruction to resume elsewhere
+            match hook::take_restart() {
+                Some(rip) => c.set_rip(rip),
+                None => break,
+            }
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
 }
 
 /// Stop emulation
 ///
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_stop(p: bochscpu_cpu_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_stop(p: bochscpu_cpu_t) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_run_state(RunState::Stop);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_state(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_state_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_state(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_state_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.state();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_state(p: bochscpu_cpu_t, s: *const bochscpu_cpu_state_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_state(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_state_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_state(&*s)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_set_state_no_flush(
     p: bochscpu_cpu_t,
     s: *const bochscpu_cpu_state_t,
-) {
-    unsafe {
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_state_no_flush(&*s)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_exception(p: bochscpu_cpu_t, vector: u32, error: u16) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_exception(
+    p: bochscpu_cpu_t,
+    vector: u32,
+    error: u16,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_exception(vector, Some(error))
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rax(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rax()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rax(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rax(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rax(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rcx(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rcx()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rcx(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rcx(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rcx(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rdx(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rdx()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rdx(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rdx(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rdx(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rbx(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rbx()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rbx(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rbx(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rbx(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rsp(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rsp()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rsp(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rsp(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rsp(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rbp(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rbp()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rbp(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rbp(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rbp(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rsi(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rsi()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rsi(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rsi(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rsi(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rdi(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rdi()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rdi(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rdi(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rdi(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r8(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r8()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r8(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r8(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r8(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r9(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r9()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r9(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r9(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r9(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r10(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r10()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r10(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r10(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r10(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r11(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r11()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r11(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r11(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r11(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r12(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r12()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r12(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r12(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r12(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r13(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r13()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r13(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r13(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r13(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r14(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r14()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r14(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r14(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r14(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_r15(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.r15()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_r15(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_r15(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_r15(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rip(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rip()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rip(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rip(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rip(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_rflags(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.rflags()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_rflags(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_rflags(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_rflags(val);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_es(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_es(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.es();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_es(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_es(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_es(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_cs(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_cs(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.cs();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_cs(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_cs(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_cs(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_ss(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_ss(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.ss();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_ss(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_ss(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_ss(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_ds(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_ds(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.ds();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_ds(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_ds(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_ds(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_fs(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_fs(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.fs();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_fs(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_fs(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_fs(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_gs(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_gs(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.gs();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_gs(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_gs(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_gs(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_ldtr(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_ldtr(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.ldtr();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_ldtr(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_ldtr(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_ldtr(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_tr(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_tr(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.tr();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_tr(p: bochscpu_cpu_t, s: *const bochscpu_cpu_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_tr(
+    p: bochscpu_cpu_t,
+    s: *const bochscpu_cpu_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_tr(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_gdtr(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_global_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_gdtr(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_global_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.gdtr();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_set_gdtr(
     p: bochscpu_cpu_t,
     s: *const bochscpu_cpu_global_seg_t,
-) {
-    unsafe {
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_gdtr(*s);
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_idtr(p: bochscpu_cpu_t, s: *mut bochscpu_cpu_global_seg_t) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_idtr(
+    p: bochscpu_cpu_t,
+    s: *mut bochscpu_cpu_global_seg_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         *s = c.idtr();
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_set_idtr(
     p: bochscpu_cpu_t,
     s: *const bochscpu_cpu_global_seg_t,
-) {
-    unsafe {
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_idtr(*s);
-    }
+    })
+}
+
+/// Get cr0
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_cr0(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.cr0() as u64
+    })
 }
 
-// TODO crX/drX
+/// Set cr0
+///
+/// The state is reloaded as by `bochscpu_cpu_set_state()`, so the cpu mode,
+/// which CR0.PE and CR0.PG select, is recomputed and cached translations are
+/// flushed. CR0.WP changes take effect on the next access.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_cr0(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        let mut s = c.state();
+        s.cr0 = val as _;
+        c.set_state(&s);
+    })
+}
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_cr2(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.cr2()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_cr2(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_cr2(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_cr2(val)
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_cpu_cr3(p: bochscpu_cpu_t) -> u64 {
-    unsafe {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.cr3()
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_cr3(p: bochscpu_cpu_t, val: u64) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_cr3(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_cr3(val)
-    }
+    })
 }
 
+/// Get cr4
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_zmm(
-    p: bochscpu_cpu_t,
-    idx: usize,
-    z: *mut bochscpu_cpu_zmm_t,
-) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_cr4(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
-        *z = c.zmm(idx);
-    }
+        c.cr4() as u64
+    })
 }
 
+/// Set cr4
+///
+/// The state is reloaded as by `bochscpu_cpu_set_state()`, so the cpu mode is
+/// recomputed and cached translations are flushed, since CR4.PAE, CR4.LA57,
+/// CR4.SMEP and CR4.SMAP change how addresses are translated and checked.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_cpu_set_zmm(
-    p: bochscpu_cpu_t,
-    idx: usize,
-    z: *const bochscpu_cpu_zmm_t,
-) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_cpu_set_cr4(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        let mut s = c.state();
+        s.cr4 = val as _;
+        c.set_state(&s);
+    })
+}
+
+/// Get cr8, the task priority register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_cr8(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.cr8()
+    })
+}
+
+/// Set cr8, the task priority register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_cr8(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_cr8(val);
+    })
+}
+
+/// Get xcr0, the mask of state components enabled for XSAVE
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_xcr0(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.xcr0() as u64
+    })
+}
+
+/// Set xcr0, the mask of state components enabled for XSAVE
+///
+/// The value is not checked against the components the cpu supports.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_xcr0(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_xcr0(val as _);
+    })
+}
+
+/// Get dr0, the linear address of hardware breakpoint 0
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr0(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr0()
+    })
+}
+
+/// Set dr0, the linear address of hardware breakpoint 0
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr0(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr0(val);
+    })
+}
+
+/// Get dr1, the linear address of hardware breakpoint 1
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr1(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr1()
+    })
+}
+
+/// Set dr1, the linear address of hardware breakpoint 1
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr1(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr1(val);
+    })
+}
+
+/// Get dr2, the linear address of hardware breakpoint 2
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr2(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr2()
+    })
+}
+
+/// Set dr2, the linear address of hardware breakpoint 2
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr2(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr2(val);
+    })
+}
+
+/// Get dr3, the linear address of hardware breakpoint 3
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr3(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr3()
+    })
+}
+
+/// Set dr3, the linear address of hardware breakpoint 3
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr3(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr3(val);
+    })
+}
+
+/// Get dr6, the debug status register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr6(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr6() as u64
+    })
+}
+
+/// Set dr6, the debug status register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr6(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr6(val as _);
+    })
+}
+
+/// Get dr7, the debug control register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_dr7(p: bochscpu_cpu_t) -> u64 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.dr7() as u64
+    })
+}
+
+/// Set dr7, the debug control register
+///
+/// Breakpoints enabled in dr7 trigger on the addresses in dr0-dr3.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_dr7(p: bochscpu_cpu_t, val: u64) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_dr7(val as _);
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_zmm(
+    p: bochscpu_cpu_t,
+    idx: usize,
+    z: *mut bochscpu_cpu_zmm_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        *z = c.zmm(idx);
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_zmm(
+    p: bochscpu_cpu_t,
+    idx: usize,
+    z: *const bochscpu_cpu_zmm_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
         let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
 
         c.set_zmm(idx, *z)
+    })
+}
+
+pub const BOCHSCPU_MSR_TSC: u32 = 0x10;
+pub const BOCHSCPU_MSR_APIC_BASE: u32 = 0x1b;
+pub const BOCHSCPU_MSR_SYSENTER_CS: u32 = 0x174;
+pub const BOCHSCPU_MSR_SYSENTER_ESP: u32 = 0x175;
+pub const BOCHSCPU_MSR_SYSENTER_EIP: u32 = 0x176;
+pub const BOCHSCPU_MSR_PAT: u32 = 0x277;
+pub const BOCHSCPU_MSR_U_CET: u32 = 0x6a0;
+pub const BOCHSCPU_MSR_S_CET: u32 = 0x6a2;
+pub const BOCHSCPU_MSR_PL0_SSP: u32 = 0x6a4;
+pub const BOCHSCPU_MSR_PL1_SSP: u32 = 0x6a5;
+pub const BOCHSCPU_MSR_PL2_SSP: u32 = 0x6a6;
+pub const BOCHSCPU_MSR_PL3_SSP: u32 = 0x6a7;
+pub const BOCHSCPU_MSR_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6a8;
+pub const BOCHSCPU_MSR_EFER: u32 = 0xc000_0080;
+pub const BOCHSCPU_MSR_STAR: u32 = 0xc000_0081;
+pub const BOCHSCPU_MSR_LSTAR: u32 = 0xc000_0082;
+pub const BOCHSCPU_MSR_CSTAR: u32 = 0xc000_0083;
+pub const BOCHSCPU_MSR_FMASK: u32 = 0xc000_0084;
+pub const BOCHSCPU_MSR_FS_BASE: u32 = 0xc000_0100;
+pub const BOCHSCPU_MSR_GS_BASE: u32 = 0xc000_0101;
+pub const BOCHSCPU_MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+pub const BOCHSCPU_MSR_TSC_AUX: u32 = 0xc000_0103;
+
+/// MSRs accessible through `bochscpu_cpu_rdmsr()` and `bochscpu_cpu_wrmsr()`
+///
+/// This is the subset of the MSRs implemented by the core which are part of
+/// the saved cpu state. Others, such as the MTRRs or SPEC_CTRL, are not
+/// reachable from here.
+const MSRS: [u32; 22] = [
+    BOCHSCPU_MSR_TSC,
+    BOCHSCPU_MSR_APIC_BASE,
+    BOCHSCPU_MSR_SYSENTER_CS,
+    BOCHSCPU_MSR_SYSENTER_ESP,
+    BOCHSCPU_MSR_SYSENTER_EIP,
+    BOCHSCPU_MSR_PAT,
+    BOCHSCPU_MSR_U_CET,
+    BOCHSCPU_MSR_S_CET,
+    BOCHSCPU_MSR_PL0_SSP,
+    BOCHSCPU_MSR_PL1_SSP,
+    BOCHSCPU_MSR_PL2_SSP,
+    BOCHSCPU_MSR_PL3_SSP,
+    BOCHSCPU_MSR_INTERRUPT_SSP_TABLE_ADDR,
+    BOCHSCPU_MSR_EFER,
+    BOCHSCPU_MSR_STAR,
+    BOCHSCPU_MSR_LSTAR,
+    BOCHSCPU_MSR_CSTAR,
+    BOCHSCPU_MSR_FMASK,
+    BOCHSCPU_MSR_FS_BASE,
+    BOCHSCPU_MSR_GS_BASE,
+    BOCHSCPU_MSR_KERNEL_GS_BASE,
+    BOCHSCPU_MSR_TSC_AUX,
+];
+
+// EFER.SCE, LME, LMA, NXE, SVME, LMSLE, FFXSR and TCE
+const EFER_VALID: u64 = 0xfd01;
+const EFER_LME: u64 = 1 << 8;
+// set by the cpu when long mode is activated, WRMSR leaves it alone
+const EFER_LMA: u64 = 1 << 10;
+
+/// Physical address width of the emulated model, BX_PHY_ADDRESS_WIDTH
+const PHY_ADDRESS_WIDTH: u32 = 40;
+// bits 7:0, 9 and those above the physical address width
+const APIC_BASE_RSVD: u64 = !((1 << PHY_ADDRESS_WIDTH) - 1) | 0x2ff;
+
+unsafe fn msr_read(c: &Cpu, msr: u32) -> Option<u64> {
+    Some(unsafe {
+        match msr {
+            BOCHSCPU_MSR_TSC => c.tsc(),
+            BOCHSCPU_MSR_APIC_BASE => c.apic_base(),
+            BOCHSCPU_MSR_SYSENTER_CS => c.sysenter_cs(),
+            BOCHSCPU_MSR_SYSENTER_ESP => c.sysenter_esp(),
+            BOCHSCPU_MSR_SYSENTER_EIP => c.sysenter_eip(),
+            BOCHSCPU_MSR_PAT => c.pat(),
+            BOCHSCPU_MSR_U_CET => c.cet_control_u(),
+            BOCHSCPU_MSR_S_CET => c.cet_control_s(),
+            BOCHSCPU_MSR_PL0_SSP => c.pl0_ssp(),
+            BOCHSCPU_MSR_PL1_SSP => c.pl1_ssp(),
+            BOCHSCPU_MSR_PL2_SSP => c.pl2_ssp(),
+            BOCHSCPU_MSR_PL3_SSP => c.pl3_ssp(),
+            BOCHSCPU_MSR_INTERRUPT_SSP_TABLE_ADDR => c.interrupt_ssp_table(),
+            BOCHSCPU_MSR_EFER => c.efer() as u64,
+            BOCHSCPU_MSR_STAR => c.star(),
+            BOCHSCPU_MSR_LSTAR => c.lstar(),
+            BOCHSCPU_MSR_CSTAR => c.cstar(),
+            BOCHSCPU_MSR_FMASK => c.sfmask(),
+            BOCHSCPU_MSR_FS_BASE => c.fs().base,
+            BOCHSCPU_MSR_GS_BASE => c.gs().base,
+            BOCHSCPU_MSR_KERNEL_GS_BASE => c.kernel_gs_base(),
+            BOCHSCPU_MSR_TSC_AUX => c.tsc_aux(),
+            _ => return None,
+        }
+    })
+}
+
+/// Check a value the way WRMSR does before it is accepted
+///
+/// These are the cases where the guest would take a #GP instead.
+unsafe fn msr_check(c: &Cpu, msr: u32, val: u64) -> Result<(), bochscpu_error_t> {
+    let (cr0, cr4) = unsafe { (c.cr0(), c.cr4()) };
+
+    // long mode can not be entered or left with paging on
+    let paging = cr0 & (1 << 31) != 0;
+    if msr == BOCHSCPU_MSR_EFER && paging && (val ^ unsafe { c.efer() } as u64) & EFER_LME != 0 {
+        return Err(bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT);
+    }
+
+    let reserved = match msr {
+        BOCHSCPU_MSR_EFER => val & !EFER_VALID != 0,
+        BOCHSCPU_MSR_APIC_BASE => val & APIC_BASE_RSVD != 0,
+        // one memory type per byte, 2 and 3 are reserved
+        BOCHSCPU_MSR_PAT => val.to_le_bytes().iter().any(|&t| matches!(t, 2 | 3 | 8..)),
+        BOCHSCPU_MSR_FMASK | BOCHSCPU_MSR_TSC_AUX => val >> 32 != 0,
+        _ => false,
+    };
+    if reserved {
+        return Err(bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT);
+    }
+
+    let address = matches!(
+        msr,
+        BOCHSCPU_MSR_SYSENTER_ESP
+            | BOCHSCPU_MSR_SYSENTER_EIP
+            | BOCHSCPU_MSR_PL0_SSP
+            | BOCHSCPU_MSR_PL1_SSP
+            | BOCHSCPU_MSR_PL2_SSP
+            | BOCHSCPU_MSR_PL3_SSP
+            | BOCHSCPU_MSR_INTERRUPT_SSP_TABLE_ADDR
+            | BOCHSCPU_MSR_LSTAR
+            | BOCHSCPU_MSR_CSTAR
+            | BOCHSCPU_MSR_FS_BASE
+            | BOCHSCPU_MSR_GS_BASE
+            | BOCHSCPU_MSR_KERNEL_GS_BASE
+    );
+    let canonical = if cr4 & (1 << 12) != 0 {
+        paging::is_canonical_la57(val)
+    } else {
+        paging::is_canonical(val)
+    };
+    if address && !canonical {
+        return Err(bochscpu_error_t::BOCHSCPU_ERROR_NON_CANONICAL);
+    }
+
+    Ok(())
+}
+
+unsafe fn msr_write(c: &Cpu, msr: u32, val: u64) -> Option<()> {
+    unsafe {
+        match msr {
+            BOCHSCPU_MSR_TSC => c.set_tsc(val),
+            BOCHSCPU_MSR_APIC_BASE => c.set_apic_base(val),
+            BOCHSCPU_MSR_SYSENTER_CS => c.set_sysenter_cs(val),
+            BOCHSCPU_MSR_SYSENTER_ESP => c.set_sysenter_esp(val),
+            BOCHSCPU_MSR_SYSENTER_EIP => c.set_sysenter_eip(val),
+            BOCHSCPU_MSR_PAT => c.set_pat(val),
+            BOCHSCPU_MSR_U_CET => c.set_cet_control_u(val),
+            BOCHSCPU_MSR_S_CET => c.set_cet_control_s(val),
+            BOCHSCPU_MSR_PL0_SSP => c.set_pl0_ssp(val),
+            BOCHSCPU_MSR_PL1_SSP => c.set_pl1_ssp(val),
+            BOCHSCPU_MSR_PL2_SSP => c.set_pl2_ssp(val),
+            BOCHSCPU_MSR_PL3_SSP => c.set_pl3_ssp(val),
+            BOCHSCPU_MSR_INTERRUPT_SSP_TABLE_ADDR => c.set_interrupt_ssp_table(val),
+            BOCHSCPU_MSR_EFER => {
+                let val = (val & !EFER_LMA) | (c.efer() as u64 & EFER_LMA);
+
+                // LME takes part in selecting the cpu mode
+                c.set_efer(val as _);
+                c.set_mode();
+            }
+            BOCHSCPU_MSR_STAR => c.set_star(val),
+            BOCHSCPU_MSR_LSTAR => c.set_lstar(val),
+            BOCHSCPU_MSR_CSTAR => c.set_cstar(val),
+            BOCHSCPU_MSR_FMASK => c.set_sfmask(val),
+            BOCHSCPU_MSR_FS_BASE => c.set_fs(Seg {
+                base: val,
+                ..c.fs()
+            }),
+            BOCHSCPU_MSR_GS_BASE => c.set_gs(Seg {
+                base: val,
+                ..c.gs()
+            }),
+            BOCHSCPU_MSR_KERNEL_GS_BASE => c.set_kernel_gs_base(val),
+            BOCHSCPU_MSR_TSC_AUX => c.set_tsc_aux(val),
+            _ => return None,
+        }
     }
+
+    Some(())
+}
+
+fn unsupported_msr(msr: u32) -> bochscpu_status_t {
+    fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_UNSUPPORTED_MSR,
+        &format!("msr {:#x} is not accessible through this api", msr),
+    )
+}
+
+/// Read an MSR
+///
+/// Only the MSRs listed by `bochscpu_cpu_msr_list()` can be read.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_UNSUPPORTED_MSR` if the MSR
+/// is not one of those listed, in which case `val` is not written to.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+/// `val` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_rdmsr(
+    p: bochscpu_cpu_t,
+    msr: u32,
+    val: *mut u64,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        match msr_read(&c, msr) {
+            Some(v) => {
+                *val = v;
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            None => unsupported_msr(msr),
+        }
+    })
+}
+
+/// Write an MSR
+///
+/// Only the MSRs listed by `bochscpu_cpu_msr_list()` can be written. Values
+/// the guest could not write either, because a reserved bit is set or an
+/// address is not canonical, are rejected. Writing EFER recomputes the cpu
+/// mode. EFER.LMA is read-only and keeps its value, use
+/// `bochscpu_cpu_set_state()` to load a cpu already in long mode.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_UNSUPPORTED_MSR` if the MSR
+/// is not one of those listed. With `BOCHSCPU_ERROR_RESERVED_BIT` or
+/// `BOCHSCPU_ERROR_NON_CANONICAL` if the value would make WRMSR raise #GP,
+/// or `BOCHSCPU_ERROR_INVALID_ARGUMENT` if it changes EFER.LME while CR0.PG
+/// is set. The MSR is left unchanged on failure.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_wrmsr(
+    p: bochscpu_cpu_t,
+    msr: u32,
+    val: u64,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        if let Err(kind) = msr_check(&c, msr, val) {
+            return fail(
+                kind,
+                &format!(
+                    "{} writing {:#x} to msr {:#x}",
+                    kind.description(),
+                    val,
+                    msr
+                ),
+            );
+        }
+
+        match msr_write(&c, msr, val) {
+            Some(()) => bochscpu_status_t::BOCHSCPU_STATUS_OK,
+            None => unsupported_msr(msr),
+        }
+    })
+}
+
+/// Enumerate supported MSRs
+///
+/// Copies up to `len` supported MSR indices into `msrs`. `msrs` may be NULL if
+/// `len` is zero.
+///
+/// # Returns
+///
+/// The total number of supported MSRs, which may be larger than `len`.
+///
+/// # Safety
+///
+/// `msrs` must be valid for writes of `len` elements.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_msr_list(msrs: *mut u32, len: usize) -> usize {
+    guard_or(0, || unsafe {
+        let n = len.min(MSRS.len());
+
+        if n != 0 {
+            ptr::copy_nonoverlapping(MSRS.as_ptr(), msrs, n);
+        }
+
+        MSRS.len()
+    })
+}
+
+/// Get the x87 FPU control word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_fpcw(p: bochscpu_cpu_t) -> u16 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.fpcw()
+    })
+}
+
+/// Set the x87 FPU control word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_fpcw(p: bochscpu_cpu_t, val: u16) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_fpcw(val);
+    })
+}
+
+/// Get the x87 FPU status word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_fpsw(p: bochscpu_cpu_t) -> u16 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.fpsw()
+    })
+}
+
+/// Set the x87 FPU status word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_fpsw(p: bochscpu_cpu_t, val: u16) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_fpsw(val);
+    })
+}
+
+/// Get the x87 FPU tag word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_fptw(p: bochscpu_cpu_t) -> u16 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.fptw()
+    })
 }
 
-// TODO fp/msr
+/// Set the x87 FPU tag word
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_fptw(p: bochscpu_cpu_t, val: u16) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_fptw(val);
+    })
+}
+
+/// Get the x87 FPU last instruction opcode
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_fpop(p: bochscpu_cpu_t) -> u16 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.fpop()
+    })
+}
+
+/// Set the x87 FPU last instruction opcode
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_fpop(p: bochscpu_cpu_t, val: u16) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_fpop(val);
+    })
+}
+
+fn bad_st(idx: usize) -> bochscpu_status_t {
+    fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+        &format!("st{} is not a valid x87 register", idx),
+    )
+}
+
+/// Get x87 FPU register ST(idx)
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if idx is not in the range 0-7.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+/// `f` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_fpst(
+    p: bochscpu_cpu_t,
+    idx: usize,
+    f: *mut bochscpu_cpu_float80_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        if idx >= 8 {
+            return bad_st(idx);
+        }
+
+        *f = c.fpst(idx);
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Set x87 FPU register ST(idx)
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if idx is not in the range 0-7.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+/// `f` must point to a valid `bochscpu_cpu_float80_t`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_fpst(
+    p: bochscpu_cpu_t,
+    idx: usize,
+    f: *const bochscpu_cpu_float80_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        if idx >= 8 {
+            return bad_st(idx);
+        }
+
+        c.set_fpst(idx, *f);
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Get the SSE control/status register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_mxcsr(p: bochscpu_cpu_t) -> u32 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.mxcsr()
+    })
+}
+
+/// Set the SSE control/status register
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_mxcsr(p: bochscpu_cpu_t, val: u32) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_mxcsr(val);
+    })
+}
+
+/// Get the mask of supported MXCSR bits
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_mxcsr_mask(p: bochscpu_cpu_t) -> u32 {
+    guard_or(0, || unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.mxcsr_mask()
+    })
+}
+
+/// Set the mask of supported MXCSR bits
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_set_mxcsr_mask(
+    p: bochscpu_cpu_t,
+    val: u32,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        c.set_mxcsr_mask(val);
+    })
+}
+
+/// Describe an access made by the cpu in its current mode
+///
+/// Fills in `access` for an access of kind `kind`, one of the
+/// `BOCHSCPU_HOOK_MEM_*` constants, using the current CPL, CR0.WP, CR4.SMEP,
+/// CR4.SMAP, CR4.LA57, EFER.NXE and RFLAGS.AC. The fields can be adjusted
+/// before use, for example clearing `user` to check a user pointer the way
+/// kernel code would dereference it.
+///
+/// # Safety
+///
+/// `p` must be a cpu returned by `bochscpu_cpu_new()` or `bochscpu_cpu_from()`,
+/// and `access` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_mem_access(
+    p: bochscpu_cpu_t,
+    kind: u32,
+    access: *mut bochscpu_mem_access_t,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let c: ManuallyDrop<Box<Cpu>> = ManuallyDrop::new(Box::from_raw(p as _));
+
+        let (cr0, cr4) = (c.cr0(), c.cr4());
+        *access = bochscpu_mem_access_t {
+            access: kind,
+            user: c.cs().selector & 3 == 3,
+            wp: cr0 & (1 << 16) != 0,
+            smep: cr4 & (1 << 20) != 0,
+            smap: cr4 & (1 << 21) != 0,
+            ac: c.rflags() & (1 << 18) != 0,
+            nxe: c.efer() & (1 << 11) != 0,
+            la57: cr4 & (1 << 12) != 0,
+        };
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lock;
+
+    #[test]
+    fn control_registers() {
+        let _l = lock();
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x300);
+
+            bochscpu_cpu_set_cr0(p, 0x8000_0031);
+            bochscpu_cpu_set_cr4(p, 0x6f8);
+            bochscpu_cpu_set_cr8(p, 0xf);
+            bochscpu_cpu_set_dr7(p, 0x401);
+            assert_eq!(bochscpu_cpu_cr0(p), 0x8000_0031);
+            assert_eq!(bochscpu_cpu_cr4(p), 0x6f8);
+            assert_eq!(bochscpu_cpu_cr8(p), 0xf);
+            assert_eq!(bochscpu_cpu_dr7(p), 0x401);
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+
+    #[test]
+    fn msr_round_trip() {
+        let _l = lock();
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x301);
+            let mut v = 0;
+
+            for (msr, val) in [
+                (BOCHSCPU_MSR_EFER, 0x901),
+                (BOCHSCPU_MSR_LSTAR, 0xffff_f800_1234_5678),
+                (BOCHSCPU_MSR_GS_BASE, 0x7ff7_0000_0000),
+                (BOCHSCPU_MSR_PAT, 0x0007_0406_0007_0406),
+            ] {
+                assert_eq!(
+                    bochscpu_cpu_wrmsr(p, msr, val),
+                    bochscpu_status_t::BOCHSCPU_STATUS_OK
+                );
+                assert_eq!(
+                    bochscpu_cpu_rdmsr(p, msr, &mut v),
+                    bochscpu_status_t::BOCHSCPU_STATUS_OK
+                );
+                assert_eq!(v, val);
+            }
+
+            // the base MSRs alias the segment bases
+            let mut gs = Seg::default();
+            bochscpu_cpu_gs(p, &mut gs);
+            assert_eq!(gs.base, 0x7ff7_0000_0000);
+
+            // rejected writes leave the MSR alone
+            for (msr, val, kind) in [
+                (
+                    BOCHSCPU_MSR_EFER,
+                    0x901 | 1 << 2,
+                    bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT,
+                ),
+                (
+                    BOCHSCPU_MSR_APIC_BASE,
+                    0xfee0_0900 | 1 << PHY_ADDRESS_WIDTH,
+                    bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT,
+                ),
+                (
+                    BOCHSCPU_MSR_LSTAR,
+                    0x8000_0000_0000,
+                    bochscpu_error_t::BOCHSCPU_ERROR_NON_CANONICAL,
+                ),
+                (
+                    BOCHSCPU_MSR_PAT,
+                    0x2,
+                    bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT,
+                ),
+                (0x2ff, 0, bochscpu_error_t::BOCHSCPU_ERROR_UNSUPPORTED_MSR),
+            ] {
+                assert_eq!(
+                    bochscpu_cpu_wrmsr(p, msr, val),
+                    bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+                );
+                assert_eq!(crate::error::bochscpu_last_error(), kind);
+            }
+            bochscpu_cpu_rdmsr(p, BOCHSCPU_MSR_EFER, &mut v);
+            assert_eq!(v, 0x901);
+
+            // LMA is read-only
+            bochscpu_cpu_wrmsr(p, BOCHSCPU_MSR_EFER, 0xd01);
+            bochscpu_cpu_rdmsr(p, BOCHSCPU_MSR_EFER, &mut v);
+            assert_eq!(v, 0x901);
+
+            // LME is fixed while paging is on
+            bochscpu_cpu_set_cr0(p, 0x8000_0031);
+            assert_eq!(
+                bochscpu_cpu_wrmsr(p, BOCHSCPU_MSR_EFER, 0x801),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+            assert_eq!(
+                crate::error::bochscpu_last_error(),
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                bochscpu_cpu_wrmsr(p, BOCHSCPU_MSR_EFER, 0x101),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+
+    #[test]
+    fn cr0_wp() {
+        use crate::hook::bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_WRITE;
+        use crate::mem::bochscpu_mem_virt_translate_access;
+        use crate::pt::{bochscpu_pt_builder_cr3, bochscpu_pt_builder_delete};
+        use crate::testutil::{builder, map, page};
+
+        let _l = lock();
+        page(0xe1_4000);
+
+        // read-only and supervisor only
+        let b = builder(0xe1_0000, 4, false);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        let gva = 0x1000_0000;
+        map(b, gva, 0xe1_4000, 0);
+        unsafe { bochscpu_pt_builder_delete(b) };
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x307);
+            let mut gpa = 0;
+
+            // supervisor writes to a read-only page fault only with CR0.WP
+            for (cr0, status) in [
+                (0x8000_0031, bochscpu_status_t::BOCHSCPU_STATUS_OK),
+                (0x8001_0031, bochscpu_status_t::BOCHSCPU_STATUS_ERROR),
+                (0x8000_0031, bochscpu_status_t::BOCHSCPU_STATUS_OK),
+            ] {
+                let mut a = mem::zeroed();
+
+                bochscpu_cpu_set_cr0(p, cr0);
+                bochscpu_cpu_mem_access(p, BOCHSCPU_HOOK_MEM_WRITE as u32, &mut a);
+                assert_eq!(
+                    bochscpu_mem_virt_translate_access(cr3, gva, &a, &mut gpa, ptr::null_mut()),
+                    status
+                );
+            }
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+
+    #[test]
+    fn mem_access() {
+        use crate::hook::bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_WRITE;
+        use crate::mem::bochscpu_mem_virt_translate_access;
+        use crate::paging::{BOCHSCPU_PF_PRESENT, BOCHSCPU_PF_WRITE, bochscpu_mem_fault_t};
+        use crate::pt::{bochscpu_pt_builder_cr3, bochscpu_pt_builder_delete};
+        use crate::testutil::{builder, map, page, w64};
+
+        let _l = lock();
+        page(0xe0_4000);
+
+        // read-only and supervisor only, the PD is at 0xe0_2000
+        let b = builder(0xe0_0000, 4, false);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        let gva = 0x1000_0000;
+        map(b, gva, 0xe0_4000, 0);
+        unsafe { bochscpu_pt_builder_delete(b) };
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x306);
+            let mut a = mem::zeroed();
+            let mut gpa = 0;
+            let mut f = bochscpu_mem_fault_t::default();
+
+            bochscpu_cpu_set_cr0(p, 0x8001_0031);
+            bochscpu_cpu_mem_access(p, BOCHSCPU_HOOK_MEM_WRITE as u32, &mut a);
+            assert!(a.wp && !a.user);
+            assert_eq!(
+                bochscpu_mem_virt_translate_access(cr3, gva, &a, &mut gpa, &mut f),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+            assert_eq!(
+                crate::error::bochscpu_last_error(),
+                bochscpu_error_t::BOCHSCPU_ERROR_ACCESS_VIOLATION
+            );
+            assert_eq!(f.error_code, BOCHSCPU_PF_PRESENT | BOCHSCPU_PF_WRITE);
+
+            // a PT which was never inserted is not a page fault
+            w64(0xe0_2000 + ((gva >> 21) & 0x1ff) * 8 + 8, 0xe0_f000 | 7);
+            let mut f = bochscpu_mem_fault_t::default();
+            assert_eq!(
+                bochscpu_mem_virt_translate_access(cr3, gva + 0x20_0000, &a, &mut gpa, &mut f),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+            assert_eq!(
+                crate::error::bochscpu_last_error(),
+                bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT
+            );
+            assert_eq!(f, bochscpu_mem_fault_t::default());
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+
+    #[test]
+    fn stale_restart() {
+        let _l = lock();
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x309);
+            bochscpu_cpu_set_rip(p, 0x1000);
+
+            // left behind by a run which returned before taking it
+            hook::restart(0x309, 0x2000);
+            assert_eq!(
+                bochscpu_cpu_run(p, ptr::null_mut()),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(bochscpu_cpu_rip(p), 0x1000);
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+
+    #[test]
+    fn fpu_registers() {
+        let _l = lock();
+
+        unsafe {
+            let p = bochscpu_cpu_new(0x302);
+
+            bochscpu_cpu_set_fpcw(p, 0x37f);
+            bochscpu_cpu_set_mxcsr(p, 0x1f80);
+            assert_eq!(bochscpu_cpu_fpcw(p), 0x37f);
+            assert_eq!(bochscpu_cpu_mxcsr(p), 0x1f80);
+
+            let one = Float80 {
+                fraction: 1 << 63,
+                exp: 0x3fff,
+            };
+            let mut f = Float80::default();
+            assert_eq!(
+                bochscpu_cpu_set_fpst(p, 7, &one),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(
+                bochscpu_cpu_fpst(p, 7, &mut f),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(f, one);
+            assert_eq!(
+                bochscpu_cpu_fpst(p, 8, &mut f),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+
+            bochscpu_cpu_delete(p);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+
+use bochscpu::hook::*;
+use bochscpu::{Address, PhyAddress};
+
+use crate::error::{bochscpu_status_t, guard, guard_or};
+use crate::paging::{PAGE_SIZE, page_chunks};
+
+// the page shows up in bochscpu_mem_dirty_pages()
+const DIRTY_USER: u8 = 1 << 0;
+// the page may differ from the snapshot the tracker is based on
+const DIRTY_SNAPSHOT: u8 = 1 << 1;
+
+struct DirtyState {
+    pages: BTreeMap<u64, u8>,
+    // id of the snapshot which memory matches, outside of DIRTY_SNAPSHOT pages
+    baseline: Option<u64>,
+    // pages whose HVA was handed out, writes through it are never seen
+    escaped: BTreeSet<u64>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static DIRTY: Mutex<DirtyState> = Mutex::new(DirtyState {
+    pages: BTreeMap::new(),
+    baseline: None,
+    escaped: BTreeSet::new(),
+});
+
+// pages written by one running cpu, merged into DIRTY whenever it is locked
+struct Buffer {
+    pages: Mutex<BTreeSet<u64>>,
+    // the page inserted last, writes to it again skip the lock
+    last: AtomicU64,
+}
+
+const NO_PAGE: u64 = u64::MAX;
+
+static BUFFERS: Mutex<Vec<Weak<Buffer>>> = Mutex::new(Vec::new());
+
+fn buffers() -> MutexGuard<'static, Vec<Weak<Buffer>>> {
+    BUFFERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Buffer {
+    fn pages(&self) -> MutexGuard<'_, BTreeSet<u64>> {
+        self.pages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn drain_into(&self, d: &mut DirtyState) {
+        let mut pages = self.pages();
+
+        // reset under the lock so a page drained here is inserted again
+        self.last.store(NO_PAGE, Ordering::Relaxed);
+        for gpa in mem::take(&mut *pages) {
+            if enabled() {
+                *d.pages.entry(gpa).or_default() |= DIRTY_USER | DIRTY_SNAPSHOT;
+            }
+        }
+    }
+}
+
+fn dirty() -> MutexGuard<'static, DirtyState> {
+    let mut d = DIRTY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    buffers().retain(|b| match b.upgrade() {
+        Some(b) => {
+            b.drain_into(&mut d);
+            true
+        }
+        None => false,
+    });
+
+    d
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn tracking on, keeping any pages already recorded
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn mark_with(gpa: u64, len: usize, bits: u8) {
+    if !enabled() || len == 0 {
+        return;
+    }
+
+    let mut d = dirty();
+    for (pa, _, _) in page_chunks(gpa, len) {
+        *d.pages.entry(pa & !(PAGE_SIZE - 1)).or_default() |= bits;
+    }
+}
+
+/// Mark every page in `[gpa, gpa + len)` as written
+pub(crate) fn mark(gpa: u64, len: usize) {
+    mark_with(gpa, len, DIRTY_USER | DIRTY_SNAPSHOT);
+}
+
+/// Mark a page whose mapping changed as differing from the snapshot baseline
+pub(crate) fn mark_remapped(gpa: u64) {
+    dirty().escaped.remove(&(gpa & !(PAGE_SIZE - 1)));
+    mark_with(gpa, 1, DIRTY_SNAPSHOT);
+}
+
+/// Record that the HVA backing `gpa` was handed to the caller
+///
+/// The page is reported by changed_since() until it is remapped, whether or
+/// not tracking is enabled, as the caller may write through the HVA at any
+/// time.
+pub(crate) fn escape(gpa: u64) {
+    dirty().escaped.insert(gpa & !(PAGE_SIZE - 1));
+}
+
+/// Make the snapshot `id` the baseline for future restores
+pub(crate) fn rebase(id: u64) {
+    if !enabled() {
+        return;
+    }
+
+    let mut d = dirty();
+    d.pages.retain(|_, bits| {
+        *bits &= !DIRTY_SNAPSHOT;
+        *bits != 0
+    });
+    d.baseline = Some(id);
+}
+
+/// Pages which may differ from the snapshot `id`
+///
+/// Returns None if the tracker can not tell, either because tracking is off or
+/// because `id` is not the current baseline. Pages whose HVA escaped are always
+/// included.
+pub(crate) fn changed_since(id: u64) -> Option<Vec<u64>> {
+    if !enabled() {
+        return None;
+    }
+
+    let d = dirty();
+    if d.baseline != Some(id) {
+        return None;
+    }
+
+    let changed = d
+        .pages
+        .iter()
+        .filter(|(_, bits)| **bits & DIRTY_SNAPSHOT != 0)
+        .map(|(gpa, _)| *gpa)
+        .collect::<BTreeSet<_>>();
+
+    Some(changed.union(&d.escaped).copied().collect())
+}
+
+/// Records guest writes while dirty tracking is enabled
+///
+/// Each run buffers the pages it writes on its own, so guest writes never
+/// contend with other cpus. The buffer is merged into the dirty set whenever
+/// that is read, and at the latest by flush() once the run returns.
+pub(crate) struct DirtyHooks {
+    buf: Arc<Buffer>,
+}
+
+impl Default for DirtyHooks {
+    fn default() -> Self {
+        let buf = Arc::new(Buffer {
+            pages: Mutex::new(BTreeSet::new()),
+            last: AtomicU64::new(NO_PAGE),
+        });
+        buffers().push(Arc::downgrade(&buf));
+
+        Self { buf }
+    }
+}
+
+impl DirtyHooks {
+    /// Merge the pages written so far into the dirty set
+    pub(crate) fn flush(&mut self) {
+        self.buf.drain_into(&mut dirty());
+    }
+
+    fn record(&self, gpa: u64, len: usize) {
+        for (pa, _, _) in page_chunks(gpa, len) {
+            let page = pa & !(PAGE_SIZE - 1);
+            if self.buf.last.load(Ordering::Relaxed) == page {
+                continue;
+            }
+
+            let mut pages = self.buf.pages();
+            pages.insert(page);
+            self.buf.last.store(page, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Hooks for DirtyHooks {
+    // stores which hit the TLB are only reported as linear accesses
+    fn lin_access(
+        &mut self,
+        _id: u32,
+        _vaddr: Address,
+        paddr: Address,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        if matches!(rw, MemAccess::Write | MemAccess::RW) {
+            self.record(paddr, len);
+        }
+    }
+
+    fn phy_access(
+        &mut self,
+        _id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        if matches!(rw, MemAccess::Write | MemAccess::RW) {
+            self.record(paddr, len);
+        }
+    }
+}
+
+/// Enable or disable dirty page tracking
+///
+/// While enabled, guest writes to physical memory and writes made with
+/// `bochscpu_mem_phy_write()` or `bochscpu_mem_virt_write()` mark the written
+/// pages dirty. Writes made directly through an HVA are not tracked, neither
+/// through the HVA of an inserted page nor through one returned by
+/// `bochscpu_mem_phy_translate()`.
+///
+/// Disabling tracking clears the dirty set, enabling it again keeps the pages
+/// already recorded.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_mem_dirty_tracking(enable: bool) -> bochscpu_status_t {
+    guard(|| {
+        let mut d = dirty();
+
+        if !enable {
+            d.pages.clear();
+            d.baseline = None;
+        }
+        ENABLED.store(enable, Ordering::Relaxed);
+    })
+}
+
+/// Enumerate dirty pages
+///
+/// Copies up to `len` dirty GPAs, in ascending order, into `gpas`. `gpas` may
+/// be NULL if `len` is zero.
+///
+/// # Returns
+///
+/// The total number of dirty pages, which may be larger than `len`.
+///
+/// # Safety
+///
+/// `gpas` must be valid for writes of `len` elements.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_dirty_pages(gpas: *mut u64, len: usize) -> usize {
+    guard_or(0, || unsafe {
+        let d = dirty();
+        let mut dirty = d
+            .pages
+            .iter()
+            .filter(|(_, bits)| **bits & DIRTY_USER != 0)
+            .map(|(gpa, _)| *gpa);
+
+        let mut n = 0;
+        for gpa in dirty.by_ref().take(len) {
+            ptr::write(gpas.add(n), gpa);
+            n += 1;
+        }
+
+        n + dirty.count()
+    })
+}
+
+/// Clear the set of dirty pages
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_mem_dirty_clear() -> bochscpu_status_t {
+    guard(|| {
+        dirty().pages.retain(|_, bits| {
+            *bits &= !DIRTY_USER;
+            *bits != 0
+        });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lock;
+
+    fn pages() -> Vec<u64> {
+        let mut gpas = [0u64; 4];
+        let n = unsafe { bochscpu_mem_dirty_pages(gpas.as_mut_ptr(), gpas.len()) };
+
+        gpas[..n.min(gpas.len())].to_vec()
+    }
+
+    #[test]
+    fn buffered_writes() {
+        let _l = lock();
+        let tracking = enabled();
+        bochscpu_mem_dirty_tracking(false);
+        bochscpu_mem_dirty_tracking(true);
+
+        let mut h = DirtyHooks::default();
+        h.phy_access(0, 0x75_0ff8, 16, MemType::Wb, MemAccess::Write);
+        h.phy_access(0, 0x75_2000, 8, MemType::Wb, MemAccess::Read);
+
+        // merged when queried, before the run flushes
+        assert_eq!(pages(), [0x75_0000, 0x75_1000]);
+
+        // the last page is recorded again once the set was cleared
+        bochscpu_mem_dirty_clear();
+        h.lin_access(0, 0, 0x75_1000, 8, MemType::Wb, MemAccess::RW);
+        h.flush();
+        assert_eq!(pages(), [0x75_1000]);
+
+        bochscpu_mem_dirty_tracking(tracking);
+    }
+}
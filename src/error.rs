@@ -0,0 +1,205 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CString, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Status returned by FFI entry points
+///
+/// Any Rust panic raised while servicing a call is caught at the FFI boundary
+/// and reported as `BOCHSCPU_STATUS_PANIC`. Other failures are reported as
+/// `BOCHSCPU_STATUS_ERROR`. In both cases the details can be fetched with
+/// `bochscpu_last_error()` and `bochscpu_last_error_message()`.
+///
+/// Panics raised while the emulator is running, from inside hooks called by
+/// `bochscpu_cpu_run()`, would have to unwind through the emulator's C++
+/// frames and abort the process instead.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub enum bochscpu_status_t {
+    BOCHSCPU_STATUS_OK = 0,
+    BOCHSCPU_STATUS_PANIC = 1,
+    BOCHSCPU_STATUS_ERROR = 2,
+}
+
+/// Kind of the most recent failure
+///
+/// Retrieved with `bochscpu_last_error()`.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub enum bochscpu_error_t {
+    /// No failure has been recorded
+    BOCHSCPU_ERROR_NONE = 0,
+    /// A Rust panic was caught at the FFI boundary
+    BOCHSCPU_ERROR_PANIC = 1,
+    /// A NULL or otherwise invalid argument was passed in
+    BOCHSCPU_ERROR_INVALID_ARGUMENT = 2,
+    /// The GVA is not canonical
+    BOCHSCPU_ERROR_NON_CANONICAL = 3,
+    /// The PML4 entry for the GVA is not present
+    BOCHSCPU_ERROR_PML4E_NOT_PRESENT = 4,
+    /// The PDPT entry for the GVA is not present
+    BOCHSCPU_ERROR_PDPTE_NOT_PRESENT = 5,
+    /// The PD entry for the GVA is not present
+    BOCHSCPU_ERROR_PDE_NOT_PRESENT = 6,
+    /// The PT entry for the GVA is not present
+    BOCHSCPU_ERROR_PTE_NOT_PRESENT = 7,
+    /// A paging structure entry or MSR value has a reserved bit set
+    BOCHSCPU_ERROR_RESERVED_BIT = 8,
+    /// The MSR is not one of those exposed by this API, see
+    /// `bochscpu_cpu_msr_list()`
+    BOCHSCPU_ERROR_UNSUPPORTED_MSR = 9,
+    /// Serializing or deserializing a value failed
+    BOCHSCPU_ERROR_SERIALIZATION = 10,
+    /// An address which must be page aligned is not
+    BOCHSCPU_ERROR_UNALIGNED = 11,
+    /// An I/O operation failed
+    BOCHSCPU_ERROR_IO = 12,
+    /// The PML5 entry for the GVA is not present
+    BOCHSCPU_ERROR_PML5E_NOT_PRESENT = 13,
+    /// The page permissions do not allow the access
+    BOCHSCPU_ERROR_ACCESS_VIOLATION = 14,
+    /// A caller supplied allocator failed to provide memory
+    BOCHSCPU_ERROR_ALLOCATION = 15,
+    /// The output buffer was too small and the result was truncated
+    BOCHSCPU_ERROR_TRUNCATED = 16,
+    /// The GPA is not backed by an inserted page
+    BOCHSCPU_ERROR_PAGE_NOT_PRESENT = 17,
+}
+
+impl bochscpu_error_t {
+    pub(crate) fn description(self) -> &'static str {
+        use bochscpu_error_t::*;
+
+        match self {
+            BOCHSCPU_ERROR_NONE => "no error",
+            BOCHSCPU_ERROR_PANIC => "panic",
+            BOCHSCPU_ERROR_INVALID_ARGUMENT => "invalid argument",
+            BOCHSCPU_ERROR_NON_CANONICAL => "non-canonical address",
+            BOCHSCPU_ERROR_PML4E_NOT_PRESENT => "pml4e not present",
+            BOCHSCPU_ERROR_PDPTE_NOT_PRESENT => "pdpte not present",
+            BOCHSCPU_ERROR_PDE_NOT_PRESENT => "pde not present",
+            BOCHSCPU_ERROR_PTE_NOT_PRESENT => "pte not present",
+            BOCHSCPU_ERROR_RESERVED_BIT => "reserved bit set",
+            BOCHSCPU_ERROR_UNSUPPORTED_MSR => "msr not exposed",
+            BOCHSCPU_ERROR_SERIALIZATION => "serialization failed",
+            BOCHSCPU_ERROR_UNALIGNED => "address not page aligned",
+            BOCHSCPU_ERROR_IO => "i/o error",
+            BOCHSCPU_ERROR_PML5E_NOT_PRESENT => "pml5e not present",
+            BOCHSCPU_ERROR_ACCESS_VIOLATION => "access violation",
+            BOCHSCPU_ERROR_ALLOCATION => "allocation failed",
+            BOCHSCPU_ERROR_TRUNCATED => "output truncated",
+            BOCHSCPU_ERROR_PAGE_NOT_PRESENT => "gpa not present",
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(bochscpu_error_t, CString)>> =
+        const { RefCell::new(None) };
+}
+
+/// Record a failure for retrieval with `bochscpu_last_error()`
+pub(crate) fn set_last_error(kind: bochscpu_error_t, msg: &str) {
+    // interior NULs would truncate the message on the C side anyway
+    let msg = CString::new(msg.replace('\0', "\\0")).unwrap_or_default();
+
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some((kind, msg)));
+}
+
+/// Record a failure and return `BOCHSCPU_STATUS_ERROR`
+pub(crate) fn fail(kind: bochscpu_error_t, msg: &str) -> bochscpu_status_t {
+    set_last_error(kind, msg);
+
+    bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+}
+
+fn record_panic(payload: Box<dyn Any + Send>) {
+    let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "unknown panic"
+    };
+
+    set_last_error(bochscpu_error_t::BOCHSCPU_ERROR_PANIC, msg);
+}
+
+/// Run `f`, converting a panic into `BOCHSCPU_STATUS_PANIC`
+///
+/// Only panics raised on the Rust side of the boundary are caught. A panic in
+/// a hook called back by the emulator during `bochscpu_cpu_run()` reaches the
+/// C++ frames between the hook and `f` first, and aborts the process rather
+/// than unwinding back here.
+pub(crate) fn guard<F: FnOnce()>(f: F) -> bochscpu_status_t {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(()) => bochscpu_status_t::BOCHSCPU_STATUS_OK,
+        Err(e) => {
+            record_panic(e);
+            bochscpu_status_t::BOCHSCPU_STATUS_PANIC
+        }
+    }
+}
+
+/// Run `f`, returning `err` if it panics
+///
+/// Used for entry points which return a value rather than a status. Successful
+/// calls leave the last error alone, so to tell a caught panic apart from a
+/// legitimate `err` value callers must call `bochscpu_clear_last_error()`
+/// first and check `bochscpu_last_error()` for `BOCHSCPU_ERROR_PANIC` after.
+///
+/// Like `guard()`, this can not recover from panics inside hooks called by
+/// the emulator.
+pub(crate) fn guard_or<T, F: FnOnce() -> T>(err: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(e) => {
+            record_panic(e);
+            err
+        }
+    }
+}
+
+/// Get the kind of the most recent failure on this thread
+///
+/// Successful calls do not reset this, use `bochscpu_clear_last_error()` to do
+/// so explicitly.
+///
+/// # Returns
+///
+/// `BOCHSCPU_ERROR_NONE` if no failure has been recorded.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_last_error() -> bochscpu_error_t {
+    guard_or(bochscpu_error_t::BOCHSCPU_ERROR_PANIC, || {
+        LAST_ERROR.with(|e| match &*e.borrow() {
+            Some((kind, _)) => *kind,
+            None => bochscpu_error_t::BOCHSCPU_ERROR_NONE,
+        })
+    })
+}
+
+/// Get the message for the most recent failure on this thread
+///
+/// # Returns
+///
+/// A NUL terminated string owned by the library, or NULL if no failure has
+/// been recorded. The pointer is valid until the next failing call on the
+/// same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_last_error_message() -> *const c_char {
+    guard_or(ptr::null(), || {
+        LAST_ERROR.with(|e| match &*e.borrow() {
+            Some((_, s)) => s.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
+
+/// Forget the most recent failure on this thread
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_clear_last_error() -> bochscpu_status_t {
+    guard(|| LAST_ERROR.with(|e| *e.borrow_mut() = None))
+}
@@ -0,0 +1,220 @@
+use std::ffi::{CStr, c_char};
+use std::fs::{File, OpenOptions};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or};
+use crate::mem;
+use crate::paging::PAGE_SIZE;
+
+/// Map the file copy-on-write, guest writes are not written back to the file
+pub const BOCHSCPU_MEM_MAP_PRIVATE: u32 = 0;
+/// Map the file shared, guest writes are written back to the file
+pub const BOCHSCPU_MEM_MAP_SHARED: u32 = 1;
+
+struct FileMapping {
+    map: MmapMut,
+    // number of pages of this mapping which are still inserted at one or more
+    // GPAs
+    live: usize,
+}
+
+static MAPPINGS: Mutex<Vec<FileMapping>> = Mutex::new(Vec::new());
+
+fn mappings() -> MutexGuard<'static, Vec<FileMapping>> {
+    MAPPINGS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Called when the page at `hva` is no longer mapped at any GPA
+///
+/// Unmaps the file mapping backing `hva` once none of its pages are mapped.
+pub(crate) fn release(hva: usize) {
+    let mut maps = mappings();
+
+    let idx = maps.iter().position(|m| {
+        let start = m.map.as_ptr() as usize;
+        (start..start + m.map.len()).contains(&hva)
+    });
+
+    if let Some(idx) = idx {
+        maps[idx].live -= 1;
+
+        if maps[idx].live == 0 {
+            maps.swap_remove(idx);
+        }
+    }
+}
+
+fn io_error(path: &str, e: std::io::Error) -> bochscpu_status_t {
+    fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_IO,
+        &format!("mapping {}: {}", path, e),
+    )
+}
+
+fn open(path: &str, flags: u32) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(flags == BOCHSCPU_MEM_MAP_SHARED)
+        .open(path)
+}
+
+/// Back guest physical memory with a file
+///
+/// Maps `len` bytes of the file at `path`, starting at `offset`, and inserts
+/// each page at consecutive GPAs starting at `gpa`, replacing any existing
+/// mappings. If `len` is zero the rest of the file is mapped.
+///
+/// `flags` is either `BOCHSCPU_MEM_MAP_PRIVATE` or `BOCHSCPU_MEM_MAP_SHARED`.
+///
+/// The library owns the file mapping, and unmaps it once every page it backs
+/// has been removed or replaced. Pages of the mapping may also be inserted at
+/// other GPAs with `bochscpu_mem_page_insert()`, which keeps them mapped until
+/// every such GPA is removed too.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if `gpa`, `offset` or `len` is not page aligned,
+/// `len` is zero and `offset` is past the end of the file, or the file can
+/// not be mapped.
+///
+/// # Safety
+///
+/// `path` must be a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_map_file(
+    path: *const c_char,
+    gpa: u64,
+    offset: u64,
+    len: usize,
+    flags: u32,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(v) => v,
+            Err(e) => {
+                return fail(
+                    bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                    &e.to_string(),
+                );
+            }
+        };
+
+        if flags != BOCHSCPU_MEM_MAP_PRIVATE && flags != BOCHSCPU_MEM_MAP_SHARED {
+            return fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("invalid map flags {:#x}", flags),
+            );
+        }
+
+        let file = match open(path, flags) {
+            Ok(v) => v,
+            Err(e) => return io_error(path, e),
+        };
+
+        let len = match len {
+            0 => match file.metadata() {
+                Ok(m) if offset > m.len() => {
+                    return fail(
+                        bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                        &format!(
+                            "mapping {}: offset {:#x} is past the end of the file",
+                            path, offset
+                        ),
+                    );
+                }
+                Ok(m) => (m.len() - offset) as usize,
+                Err(e) => return io_error(path, e),
+            },
+            _ => len,
+        };
+
+        if (gpa | offset | len as u64) & (PAGE_SIZE - 1) != 0 {
+            return fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_UNALIGNED,
+                &format!(
+                    "mapping {} at gpa {:#x}, offset {:#x}, len {:#x}",
+                    path, gpa, offset, len
+                ),
+            );
+        }
+
+        if len == 0 {
+            return bochscpu_status_t::BOCHSCPU_STATUS_OK;
+        }
+
+        let mut opts = MmapOptions::new();
+        opts.offset(offset).len(len);
+
+        let map = match flags {
+            BOCHSCPU_MEM_MAP_SHARED => opts.map_mut(&file),
+            _ => opts.map_copy(&file),
+        };
+
+        let mut map = match map {
+            Ok(v) => v,
+            Err(e) => return io_error(path, e),
+        };
+
+        let base = map.as_mut_ptr();
+        let pages = len / PAGE_SIZE as usize;
+
+        mappings().push(FileMapping { map, live: pages });
+
+        for i in 0..pages {
+            let off = i * PAGE_SIZE as usize;
+            mem::insert(gpa + off as u64, base.add(off));
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::mem::{bochscpu_mem_page_insert, bochscpu_mem_page_remove};
+    use crate::testutil::lock;
+
+    #[test]
+    fn aliased_pages() {
+        let _l = lock();
+        let path = std::env::temp_dir().join(format!("bochscpu-ffi-{}", std::process::id()));
+        std::fs::write(&path, [0x41u8; 0x2000]).unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            assert_eq!(
+                bochscpu_mem_map_file(cpath.as_ptr(), 0x80_0000, 0, 0, BOCHSCPU_MEM_MAP_PRIVATE),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(
+                bochscpu_mem_map_file(
+                    cpath.as_ptr(),
+                    0x80_0000,
+                    0x3000,
+                    0,
+                    BOCHSCPU_MEM_MAP_PRIVATE
+                ),
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            );
+
+            let hva = mem::mapped_pages()[&0x80_0000] as *mut u8;
+            bochscpu_mem_page_insert(0x90_0000, hva);
+
+            // the alias keeps the mapping alive
+            bochscpu_mem_page_remove(0x80_0000);
+            bochscpu_mem_page_remove(0x80_1000);
+            assert_eq!(mappings().len(), 1);
+            assert_eq!(*hva, 0x41);
+
+            bochscpu_mem_page_remove(0x90_0000);
+            assert!(mappings().is_empty());
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
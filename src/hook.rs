@@ -1,106 +1,564 @@
+use std::cell::Cell;
 use std::ffi::c_void;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+use bochscpu::cpu::{Cpu, RunState};
 use bochscpu::hook::*;
+use bochscpu::opcode::instr_ilen;
 use bochscpu::{Address, PhyAddress};
 
-// these have to be hard coded otherwise cbindgen wont pick up on them
-pub const BX_INSTR_IS_JMP: u32 = 10;
-const_assert_eq!(BX_INSTR_IS_JMP, Branch::Jmp as u32);
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or, set_last_error};
 
-pub const BOCHSCPU_INSTR_IS_JMP_INDIRECT: u32 = 11;
-const_assert_eq!(BOCHSCPU_INSTR_IS_JMP_INDIRECT, Branch::JmpIndirect as u32);
+// discriminants have to be hard coded otherwise cbindgen wont pick up on them
 
-pub const BOCHSCPU_INSTR_IS_CALL: u32 = 12;
-const_assert_eq!(BOCHSCPU_INSTR_IS_CALL, Branch::Call as u32);
+/// Source of a cpu reset
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_reset_source_t {
+    BOCHSCPU_RESET_SOFTWARE = 10,
+    BOCHSCPU_RESET_HARDWARE = 11,
+}
 
-pub const BOCHSCPU_INSTR_IS_CALL_INDIRECT: u32 = 13;
-const_assert_eq!(BOCHSCPU_INSTR_IS_CALL_INDIRECT, Branch::CallIndirect as u32);
+const_assert_eq!(
+    bochscpu_reset_source_t::BOCHSCPU_RESET_SOFTWARE as u32,
+    ResetSource::Software as u32
+);
+const_assert_eq!(
+    bochscpu_reset_source_t::BOCHSCPU_RESET_HARDWARE as u32,
+    ResetSource::Hardware as u32
+);
 
-pub const BOCHSCPU_INSTR_IS_RET: u32 = 14;
-const_assert_eq!(BOCHSCPU_INSTR_IS_RET, Branch::Ret as u32);
+impl From<ResetSource> for bochscpu_reset_source_t {
+    fn from(v: ResetSource) -> Self {
+        use bochscpu_reset_source_t::*;
 
-pub const BOCHSCPU_INSTR_IS_IRET: u32 = 15;
-const_assert_eq!(BOCHSCPU_INSTR_IS_IRET, Branch::Iret as u32);
+        match v {
+            ResetSource::Software => BOCHSCPU_RESET_SOFTWARE,
+            ResetSource::Hardware => BOCHSCPU_RESET_HARDWARE,
+        }
+    }
+}
 
-pub const BOCHSCPU_INSTR_IS_INT: u32 = 16;
-const_assert_eq!(BOCHSCPU_INSTR_IS_INT, Branch::Int as u32);
+/// Kind of an unconditional or far branch
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_branch_t {
+    BOCHSCPU_INSTR_IS_JMP = 10,
+    BOCHSCPU_INSTR_IS_JMP_INDIRECT = 11,
+    BOCHSCPU_INSTR_IS_CALL = 12,
+    BOCHSCPU_INSTR_IS_CALL_INDIRECT = 13,
+    BOCHSCPU_INSTR_IS_RET = 14,
+    BOCHSCPU_INSTR_IS_IRET = 15,
+    BOCHSCPU_INSTR_IS_INT = 16,
+    BOCHSCPU_INSTR_IS_SYSCALL = 17,
+    BOCHSCPU_INSTR_IS_SYSRET = 18,
+    BOCHSCPU_INSTR_IS_SYSENTER = 19,
+    BOCHSCPU_INSTR_IS_SYSEXIT = 20,
+}
 
-pub const BOCHSCPU_INSTR_IS_SYSCALL: u32 = 17;
-const_assert_eq!(BOCHSCPU_INSTR_IS_SYSCALL, Branch::Syscall as u32);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP as u32,
+    Branch::Jmp as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP_INDIRECT as u32,
+    Branch::JmpIndirect as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL as u32,
+    Branch::Call as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL_INDIRECT as u32,
+    Branch::CallIndirect as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_RET as u32,
+    Branch::Ret as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_IRET as u32,
+    Branch::Iret as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_INT as u32,
+    Branch::Int as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSCALL as u32,
+    Branch::Syscall as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSRET as u32,
+    Branch::Sysret as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSENTER as u32,
+    Branch::Sysenter as u32
+);
+const_assert_eq!(
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSEXIT as u32,
+    Branch::Sysexit as u32
+);
 
-pub const BOCHSCPU_INSTR_IS_SYSRET: u32 = 18;
-const_assert_eq!(BOCHSCPU_INSTR_IS_SYSRET, Branch::Sysret as u32);
+impl From<Branch> for bochscpu_branch_t {
+    fn from(v: Branch) -> Self {
+        use bochscpu_branch_t::*;
+
+        match v {
+            Branch::Jmp => BOCHSCPU_INSTR_IS_JMP,
+            Branch::JmpIndirect => BOCHSCPU_INSTR_IS_JMP_INDIRECT,
+            Branch::Call => BOCHSCPU_INSTR_IS_CALL,
+            Branch::CallIndirect => BOCHSCPU_INSTR_IS_CALL_INDIRECT,
+            Branch::Ret => BOCHSCPU_INSTR_IS_RET,
+            Branch::Iret => BOCHSCPU_INSTR_IS_IRET,
+            Branch::Int => BOCHSCPU_INSTR_IS_INT,
+            Branch::Syscall => BOCHSCPU_INSTR_IS_SYSCALL,
+            Branch::Sysret => BOCHSCPU_INSTR_IS_SYSRET,
+            Branch::Sysenter => BOCHSCPU_INSTR_IS_SYSENTER,
+            Branch::Sysexit => BOCHSCPU_INSTR_IS_SYSEXIT,
+        }
+    }
+}
+
+/// Deprecated alias of `BOCHSCPU_INSTR_IS_JMP`
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP")]
+pub const BX_INSTR_IS_JMP: u32 = 10;
+
+// Rust callers used these before the typed enums existed. C callers get the
+// same names from the enums, so they are left out of the headers.
+
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP")]
+pub const BOCHSCPU_INSTR_IS_JMP: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP_INDIRECT")]
+pub const BOCHSCPU_INSTR_IS_JMP_INDIRECT: u32 =
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_JMP_INDIRECT as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL")]
+pub const BOCHSCPU_INSTR_IS_CALL: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL_INDIRECT")]
+pub const BOCHSCPU_INSTR_IS_CALL_INDIRECT: u32 =
+    bochscpu_branch_t::BOCHSCPU_INSTR_IS_CALL_INDIRECT as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_RET")]
+pub const BOCHSCPU_INSTR_IS_RET: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_RET as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_IRET")]
+pub const BOCHSCPU_INSTR_IS_IRET: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_IRET as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_INT")]
+pub const BOCHSCPU_INSTR_IS_INT: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_INT as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSCALL")]
+pub const BOCHSCPU_INSTR_IS_SYSCALL: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSCALL as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSRET")]
+pub const BOCHSCPU_INSTR_IS_SYSRET: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSRET as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSENTER")]
+pub const BOCHSCPU_INSTR_IS_SYSENTER: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSENTER as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSEXIT")]
+pub const BOCHSCPU_INSTR_IS_SYSEXIT: u32 = bochscpu_branch_t::BOCHSCPU_INSTR_IS_SYSEXIT as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_READ")]
+pub const BOCHSCPU_HOOK_MEM_READ: u32 = bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_READ as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_WRITE")]
+pub const BOCHSCPU_HOOK_MEM_WRITE: u32 = bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_WRITE as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_EXECUTE")]
+pub const BOCHSCPU_HOOK_MEM_EXECUTE: u32 = bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_EXECUTE as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_RW")]
+pub const BOCHSCPU_HOOK_MEM_RW: u32 = bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_RW as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR0")]
+pub const BOCHSCPU_HOOK_TLB_CR0: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR0 as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR3")]
+pub const BOCHSCPU_HOOK_TLB_CR3: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR3 as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR4")]
+pub const BOCHSCPU_HOOK_TLB_CR4: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR4 as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_TASKSWITCH")]
+pub const BOCHSCPU_HOOK_TLB_TASKSWITCH: u32 =
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_TASKSWITCH as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CONTEXTSWITCH")]
+pub const BOCHSCPU_HOOK_TLB_CONTEXTSWITCH: u32 =
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CONTEXTSWITCH as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVLPG")]
+pub const BOCHSCPU_HOOK_TLB_INVLPG: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVLPG as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVEPT")]
+pub const BOCHSCPU_HOOK_TLB_INVEPT: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVEPT as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVVPID")]
+pub const BOCHSCPU_HOOK_TLB_INVVPID: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVVPID as u32;
+/// cbindgen:ignore
+#[deprecated(note = "use bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVPCID")]
+pub const BOCHSCPU_HOOK_TLB_INVPCID: u32 = bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVPCID as u32;
+
+/// Kind of a memory access
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_hook_mem_t {
+    BOCHSCPU_HOOK_MEM_READ = 0,
+    BOCHSCPU_HOOK_MEM_WRITE = 1,
+    BOCHSCPU_HOOK_MEM_EXECUTE = 2,
+    BOCHSCPU_HOOK_MEM_RW = 3,
+}
 
-pub const BOCHSCPU_INSTR_IS_SYSENTER: u32 = 19;
-const_assert_eq!(BOCHSCPU_INSTR_IS_SYSENTER, Branch::Sysenter as u32);
+const_assert_eq!(
+    bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_READ as u32,
+    MemAccess::Read as u32
+);
+const_assert_eq!(
+    bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_WRITE as u32,
+    MemAccess::Write as u32
+);
+const_assert_eq!(
+    bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_EXECUTE as u32,
+    MemAccess::Execute as u32
+);
+const_assert_eq!(
+    bochscpu_hook_mem_t::BOCHSCPU_HOOK_MEM_RW as u32,
+    MemAccess::RW as u32
+);
 
-pub const BOCHSCPU_INSTR_IS_SYSEXIT: u32 = 20;
-const_assert_eq!(BOCHSCPU_INSTR_IS_SYSEXIT, Branch::Sysexit as u32);
+impl bochscpu_hook_mem_t {
+    /// Convert an access kind passed in by the caller
+    pub(crate) fn from_raw(v: u32) -> Option<Self> {
+        use bochscpu_hook_mem_t::*;
 
-pub const BOCHSCPU_HOOK_MEM_READ: u32 = 0;
-const_assert_eq!(BOCHSCPU_HOOK_MEM_READ, MemAccess::Read as u32);
+        [
+            BOCHSCPU_HOOK_MEM_READ,
+            BOCHSCPU_HOOK_MEM_WRITE,
+            BOCHSCPU_HOOK_MEM_EXECUTE,
+            BOCHSCPU_HOOK_MEM_RW,
+        ]
+        .into_iter()
+        .find(|&k| k as u32 == v)
+    }
+}
 
-pub const BOCHSCPU_HOOK_MEM_WRITE: u32 = 1;
-const_assert_eq!(BOCHSCPU_HOOK_MEM_WRITE, MemAccess::Write as u32);
+impl From<MemAccess> for bochscpu_hook_mem_t {
+    fn from(v: MemAccess) -> Self {
+        use bochscpu_hook_mem_t::*;
 
-pub const BOCHSCPU_HOOK_MEM_EXECUTE: u32 = 2;
-const_assert_eq!(BOCHSCPU_HOOK_MEM_EXECUTE, MemAccess::Execute as u32);
+        match v {
+            MemAccess::Read => BOCHSCPU_HOOK_MEM_READ,
+            MemAccess::Write => BOCHSCPU_HOOK_MEM_WRITE,
+            MemAccess::Execute => BOCHSCPU_HOOK_MEM_EXECUTE,
+            MemAccess::RW => BOCHSCPU_HOOK_MEM_RW,
+        }
+    }
+}
 
-pub const BOCHSCPU_HOOK_MEM_RW: u32 = 3;
-const_assert_eq!(BOCHSCPU_HOOK_MEM_RW, MemAccess::RW as u32);
+/// Memory type of a memory access
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_mem_type_t {
+    BOCHSCPU_MEMTYPE_UC = 0,
+    BOCHSCPU_MEMTYPE_WC = 1,
+    BOCHSCPU_MEMTYPE_RESERVED2 = 2,
+    BOCHSCPU_MEMTYPE_RESERVED3 = 3,
+    BOCHSCPU_MEMTYPE_WT = 4,
+    BOCHSCPU_MEMTYPE_WP = 5,
+    BOCHSCPU_MEMTYPE_WB = 6,
+    BOCHSCPU_MEMTYPE_UC_WEAK = 7,
+    BOCHSCPU_MEMTYPE_INVALID = 8,
+}
 
-pub const BOCHSCPU_HOOK_TLB_CR0: u32 = 10;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_CR0, TlbCntrl::MovCr0 as u32);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_UC as u32,
+    MemType::Uc as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_WC as u32,
+    MemType::Wc as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_RESERVED2 as u32,
+    MemType::Reserved2 as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_RESERVED3 as u32,
+    MemType::Reserved3 as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_WT as u32,
+    MemType::Wt as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_WP as u32,
+    MemType::Wp as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_WB as u32,
+    MemType::Wb as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_UC_WEAK as u32,
+    MemType::UcWeak as u32
+);
+const_assert_eq!(
+    bochscpu_mem_type_t::BOCHSCPU_MEMTYPE_INVALID as u32,
+    MemType::Invalid as u32
+);
 
-pub const BOCHSCPU_HOOK_TLB_CR3: u32 = 11;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_CR3, TlbCntrl::MovCr3 as u32);
+impl From<MemType> for bochscpu_mem_type_t {
+    fn from(v: MemType) -> Self {
+        use bochscpu_mem_type_t::*;
 
-pub const BOCHSCPU_HOOK_TLB_CR4: u32 = 12;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_CR4, TlbCntrl::MovCr4 as u32);
+        match v {
+            MemType::Uc => BOCHSCPU_MEMTYPE_UC,
+            MemType::Wc => BOCHSCPU_MEMTYPE_WC,
+            MemType::Reserved2 => BOCHSCPU_MEMTYPE_RESERVED2,
+            MemType::Reserved3 => BOCHSCPU_MEMTYPE_RESERVED3,
+            MemType::Wt => BOCHSCPU_MEMTYPE_WT,
+            MemType::Wp => BOCHSCPU_MEMTYPE_WP,
+            MemType::Wb => BOCHSCPU_MEMTYPE_WB,
+            MemType::UcWeak => BOCHSCPU_MEMTYPE_UC_WEAK,
+            MemType::Invalid => BOCHSCPU_MEMTYPE_INVALID,
+        }
+    }
+}
 
-pub const BOCHSCPU_HOOK_TLB_TASKSWITCH: u32 = 13;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_TASKSWITCH, TlbCntrl::TaskSwitch as u32);
+/// Cause of a TLB flush
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_tlb_cntrl_t {
+    BOCHSCPU_HOOK_TLB_CR0 = 10,
+    BOCHSCPU_HOOK_TLB_CR3 = 11,
+    BOCHSCPU_HOOK_TLB_CR4 = 12,
+    BOCHSCPU_HOOK_TLB_TASKSWITCH = 13,
+    BOCHSCPU_HOOK_TLB_CONTEXTSWITCH = 14,
+    BOCHSCPU_HOOK_TLB_INVLPG = 15,
+    BOCHSCPU_HOOK_TLB_INVEPT = 16,
+    BOCHSCPU_HOOK_TLB_INVVPID = 17,
+    BOCHSCPU_HOOK_TLB_INVPCID = 18,
+}
 
-pub const BOCHSCPU_HOOK_TLB_CONTEXTSWITCH: u32 = 14;
 const_assert_eq!(
-    BOCHSCPU_HOOK_TLB_CONTEXTSWITCH,
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR0 as u32,
+    TlbCntrl::MovCr0 as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR3 as u32,
+    TlbCntrl::MovCr3 as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CR4 as u32,
+    TlbCntrl::MovCr4 as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_TASKSWITCH as u32,
+    TlbCntrl::TaskSwitch as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_CONTEXTSWITCH as u32,
     TlbCntrl::ContextSwitch as u32
 );
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVLPG as u32,
+    TlbCntrl::InvLpg as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVEPT as u32,
+    TlbCntrl::InvEpt as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVVPID as u32,
+    TlbCntrl::InvVpid as u32
+);
+const_assert_eq!(
+    bochscpu_tlb_cntrl_t::BOCHSCPU_HOOK_TLB_INVPCID as u32,
+    TlbCntrl::InvPcid as u32
+);
+
+impl From<TlbCntrl> for bochscpu_tlb_cntrl_t {
+    fn from(v: TlbCntrl) -> Self {
+        use bochscpu_tlb_cntrl_t::*;
+
+        match v {
+            TlbCntrl::MovCr0 => BOCHSCPU_HOOK_TLB_CR0,
+            TlbCntrl::MovCr3 => BOCHSCPU_HOOK_TLB_CR3,
+            TlbCntrl::MovCr4 => BOCHSCPU_HOOK_TLB_CR4,
+            TlbCntrl::TaskSwitch => BOCHSCPU_HOOK_TLB_TASKSWITCH,
+            TlbCntrl::ContextSwitch => BOCHSCPU_HOOK_TLB_CONTEXTSWITCH,
+            TlbCntrl::InvLpg => BOCHSCPU_HOOK_TLB_INVLPG,
+            TlbCntrl::InvEpt => BOCHSCPU_HOOK_TLB_INVEPT,
+            TlbCntrl::InvVpid => BOCHSCPU_HOOK_TLB_INVVPID,
+            TlbCntrl::InvPcid => BOCHSCPU_HOOK_TLB_INVPCID,
+        }
+    }
+}
 
-pub const BOCHSCPU_HOOK_TLB_INVLPG: u32 = 15;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_INVLPG, TlbCntrl::InvLpg as u32);
+/// Cache control instruction
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_cache_cntrl_t {
+    BOCHSCPU_HOOK_CACHE_INVD = 10,
+    BOCHSCPU_HOOK_CACHE_WBINVD = 11,
+}
 
-pub const BOCHSCPU_HOOK_TLB_INVEPT: u32 = 16;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_INVEPT, TlbCntrl::InvEpt as u32);
+const_assert_eq!(
+    bochscpu_cache_cntrl_t::BOCHSCPU_HOOK_CACHE_INVD as u32,
+    CacheCntrl::Invd as u32
+);
+const_assert_eq!(
+    bochscpu_cache_cntrl_t::BOCHSCPU_HOOK_CACHE_WBINVD as u32,
+    CacheCntrl::Wbinvd as u32
+);
 
-pub const BOCHSCPU_HOOK_TLB_INVVPID: u32 = 17;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_INVVPID, TlbCntrl::InvVpid as u32);
+impl From<CacheCntrl> for bochscpu_cache_cntrl_t {
+    fn from(v: CacheCntrl) -> Self {
+        use bochscpu_cache_cntrl_t::*;
 
-pub const BOCHSCPU_HOOK_TLB_INVPCID: u32 = 18;
-const_assert_eq!(BOCHSCPU_HOOK_TLB_INVPCID, TlbCntrl::InvPcid as u32);
+        match v {
+            CacheCntrl::Invd => BOCHSCPU_HOOK_CACHE_INVD,
+            CacheCntrl::Wbinvd => BOCHSCPU_HOOK_CACHE_WBINVD,
+        }
+    }
+}
+
+/// Prefetch instruction hint
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_prefetch_hint_t {
+    BOCHSCPU_HOOK_PREFETCH_NTA = 0,
+    BOCHSCPU_HOOK_PREFETCH_T0 = 1,
+    BOCHSCPU_HOOK_PREFETCH_T1 = 2,
+    BOCHSCPU_HOOK_PREFETCH_T2 = 3,
+}
+
+const_assert_eq!(
+    bochscpu_prefetch_hint_t::BOCHSCPU_HOOK_PREFETCH_NTA as u32,
+    PrefetchHint::Nta as u32
+);
+const_assert_eq!(
+    bochscpu_prefetch_hint_t::BOCHSCPU_HOOK_PREFETCH_T0 as u32,
+    PrefetchHint::T0 as u32
+);
+const_assert_eq!(
+    bochscpu_prefetch_hint_t::BOCHSCPU_HOOK_PREFETCH_T1 as u32,
+    PrefetchHint::T1 as u32
+);
+const_assert_eq!(
+    bochscpu_prefetch_hint_t::BOCHSCPU_HOOK_PREFETCH_T2 as u32,
+    PrefetchHint::T2 as u32
+);
+
+impl From<PrefetchHint> for bochscpu_prefetch_hint_t {
+    fn from(v: PrefetchHint) -> Self {
+        use bochscpu_prefetch_hint_t::*;
+
+        match v {
+            PrefetchHint::Nta => BOCHSCPU_HOOK_PREFETCH_NTA,
+            PrefetchHint::T0 => BOCHSCPU_HOOK_PREFETCH_T0,
+            PrefetchHint::T1 => BOCHSCPU_HOOK_PREFETCH_T1,
+            PrefetchHint::T2 => BOCHSCPU_HOOK_PREFETCH_T2,
+        }
+    }
+}
+
+/// Keep emulating
+pub const BOCHSCPU_HOOK_ACTION_CONTINUE: u32 = 0;
+/// Stop once the current instruction completes, as with `bochscpu_cpu_stop()`
+pub const BOCHSCPU_HOOK_ACTION_STOP: u32 = 1;
+/// Abandon the current instruction and resume at the next one
+pub const BOCHSCPU_HOOK_ACTION_SKIP: u32 = 2;
+/// Abandon the current instruction and resume at the RIP set by the hook
+pub const BOCHSCPU_HOOK_ACTION_REDIRECT: u32 = 3;
+
+/// reset, hlt and mwait
+pub const BOCHSCPU_HOOK_CAT_CONTROL: u32 = 1 << 0;
+/// cnear_branch_taken, cnear_branch_not_taken, ucnear_branch and far_branch
+pub const BOCHSCPU_HOOK_CAT_BRANCH: u32 = 1 << 1;
+/// opcode
+pub const BOCHSCPU_HOOK_CAT_OPCODE: u32 = 1 << 2;
+/// interrupt, exception and hw_interrupt
+pub const BOCHSCPU_HOOK_CAT_INTERRUPT: u32 = 1 << 3;
+/// tlb_cntrl, cache_cntrl, prefetch_hint and clflush
+pub const BOCHSCPU_HOOK_CAT_CACHE: u32 = 1 << 4;
+/// before_execution, after_execution and repeat_iteration
+pub const BOCHSCPU_HOOK_CAT_EXECUTION: u32 = 1 << 5;
+/// inp, inp2 and outp
+pub const BOCHSCPU_HOOK_CAT_IO: u32 = 1 << 6;
+/// lin_access and phy_access
+pub const BOCHSCPU_HOOK_CAT_MEMORY: u32 = 1 << 7;
+/// wrmsr and vmexit
+pub const BOCHSCPU_HOOK_CAT_SYSTEM: u32 = 1 << 8;
+/// Every hook category
+pub const BOCHSCPU_HOOK_CAT_ALL: u32 = 0x1ff;
+
+/// Current layout version of `bochscpu_hooks_t`
+pub const BOCHSCPU_HOOKS_VERSION: u32 = 1;
 
 /// FFI Hook object
 ///
 /// Full desciptions of hook points can be found here:
 /// http://bochs.sourceforge.net/cgi-bin/lxr/source/instrument/instrumentation.txt
 ///
+/// `struct_size` must be set to `sizeof(bochscpu_hooks_t)` and `version` to
+/// `BOCHSCPU_HOOKS_VERSION` as seen by the caller when it was compiled. Hooks
+/// added to the end of the struct after that are treated as NULL. Versions
+/// newer than the library's `BOCHSCPU_HOOKS_VERSION` are rejected.
+///
+/// Version 1 added the `struct_size` and `version` header in front of `ctx`,
+/// which breaks the ABI: callers built against headers without them must be
+/// rebuilt.
+///
+/// The struct is copied when `bochscpu_cpu_run()` is called, changes made to
+/// it during the run, including from its own hooks, take effect on the next
+/// run.
+///
 /// If the hook value is NULL it will be treated as a no-op. The value of the
 /// ctx field will be passed as the first paramter to every hook and is fully
 /// controlled by the API author
+///
+/// The `*_action` hooks are called with the same arguments as their plain
+/// counterparts, after them, and return one of the `BOCHSCPU_HOOK_ACTION_*`
+/// constants. The current instruction is the one most recently passed to
+/// `before_execution`, except for `opcode_action` where it is the decoded
+/// instruction, which is skipped the next time it is about to execute. To
+/// redirect, set RIP with `bochscpu_cpu_set_rip()` before returning.
+///
+/// `opcode` and `opcode_action` are only called when the core decodes an
+/// instruction, not each time a cached decode is executed again.
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug)]
 #[repr(C)]
 pub struct bochscpu_hooks_t {
+    pub struct_size: usize,
+    pub version: u32,
+
     pub ctx: *mut c_void,
 
-    pub reset: Option<extern "C" fn(*mut c_void, u32, u32)>,
+    pub reset: Option<extern "C" fn(*mut c_void, u32, bochscpu_reset_source_t)>,
     pub hlt: Option<extern "C" fn(*mut c_void, u32)>,
     pub mwait: Option<extern "C" fn(*mut c_void, u32, u64, usize, u32)>,
 
     pub cnear_branch_taken: Option<extern "C" fn(*mut c_void, u32, u64, u64)>,
     pub cnear_branch_not_taken: Option<extern "C" fn(*mut c_void, u32, u64, u64)>,
-    pub ucnear_branch: Option<extern "C" fn(*mut c_void, u32, u32, u64, u64)>,
-    pub far_branch: Option<extern "C" fn(*mut c_void, u32, u32, u16, u64, u16, u64)>,
+    pub ucnear_branch: Option<extern "C" fn(*mut c_void, u32, bochscpu_branch_t, u64, u64)>,
+    pub far_branch: Option<extern "C" fn(*mut c_void, u32, bochscpu_branch_t, u16, u64, u16, u64)>,
 
     pub opcode:
         Option<extern "C" fn(*mut c_void, u32, *const c_void, *const u8, usize, bool, bool)>,
@@ -108,9 +566,9 @@ pub struct bochscpu_hooks_t {
     pub exception: Option<extern "C" fn(*mut c_void, u32, u32, u32)>,
     pub hw_interrupt: Option<extern "C" fn(*mut c_void, u32, u32, u16, u64)>,
 
-    pub tlb_cntrl: Option<extern "C" fn(*mut c_void, u32, u32, u64)>,
-    pub cache_cntrl: Option<extern "C" fn(*mut c_void, u32, u32)>,
-    pub prefetch_hint: Option<extern "C" fn(*mut c_void, u32, u32, u32, u64)>,
+    pub tlb_cntrl: Option<extern "C" fn(*mut c_void, u32, bochscpu_tlb_cntrl_t, u64)>,
+    pub cache_cntrl: Option<extern "C" fn(*mut c_void, u32, bochscpu_cache_cntrl_t)>,
+    pub prefetch_hint: Option<extern "C" fn(*mut c_void, u32, bochscpu_prefetch_hint_t, u32, u64)>,
     pub clflush: Option<extern "C" fn(*mut c_void, u32, u64, u64)>,
 
     pub before_execution: Option<extern "C" fn(*mut c_void, u32, *mut c_void)>,
@@ -121,40 +579,210 @@ pub struct bochscpu_hooks_t {
     pub inp2: Option<extern "C" fn(*mut c_void, u16, usize, u32)>,
     pub outp: Option<extern "C" fn(*mut c_void, u16, usize, u32)>,
 
-    pub lin_access: Option<extern "C" fn(*mut c_void, u32, u64, u64, usize, u32, u32)>,
-    pub phy_access: Option<extern "C" fn(*mut c_void, u32, u64, usize, u32, u32)>,
+    pub lin_access: Option<
+        extern "C" fn(*mut c_void, u32, u64, u64, usize, bochscpu_mem_type_t, bochscpu_hook_mem_t),
+    >,
+    pub phy_access: Option<
+        extern "C" fn(*mut c_void, u32, u64, usize, bochscpu_mem_type_t, bochscpu_hook_mem_t),
+    >,
 
     pub wrmsr: Option<extern "C" fn(*mut c_void, u32, u32, u64)>,
 
     pub vmexit: Option<extern "C" fn(*mut c_void, u32, u32, u64)>,
+
+    pub before_execution_action: Option<extern "C" fn(*mut c_void, u32, *mut c_void) -> u32>,
+    pub exception_action: Option<extern "C" fn(*mut c_void, u32, u32, u32) -> u32>,
+    pub interrupt_action: Option<extern "C" fn(*mut c_void, u32, u32) -> u32>,
+    pub opcode_action:
+        Option<extern "C" fn(*mut c_void, u32, *const c_void, *const u8, usize, bool, bool) -> u32>,
+    pub lin_access_action: Option<
+        extern "C" fn(
+            *mut c_void,
+            u32,
+            u64,
+            u64,
+            usize,
+            bochscpu_mem_type_t,
+            bochscpu_hook_mem_t,
+        ) -> u32,
+    >,
+    pub phy_access_action: Option<
+        extern "C" fn(
+            *mut c_void,
+            u32,
+            u64,
+            usize,
+            bochscpu_mem_type_t,
+            bochscpu_hook_mem_t,
+        ) -> u32,
+    >,
+}
+
+thread_local! {
+    // rip and bxInstruction_c of the instruction executing on this thread
+    static CURRENT: Cell<(u64, usize)> = const { Cell::new((0, 0)) };
+    // decoded instruction an opcode hook asked to skip
+    static SKIP_DECODED: Cell<usize> = const { Cell::new(0) };
+    // rip to resume at after bailing out of the current instruction
+    static RESTART: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Forget the instruction state a previous run on this thread left behind
+pub(crate) fn reset_run_state() {
+    CURRENT.set((0, 0));
+    SKIP_DECODED.set(0);
+    RESTART.set(None);
+}
+
+/// Take the RIP emulation should resume at, if a hook abandoned an
+/// instruction
+pub(crate) fn take_restart() -> Option<u64> {
+    RESTART.take()
+}
+
+/// Abandon the current instruction and resume at `rip`
+pub(crate) fn restart(id: u32, rip: u64) {
+    RESTART.set(Some(rip));
+
+    // bailing unwinds out of the instruction, so record the target first
+    unsafe { Cpu::from(id).set_run_state(RunState::Bail) };
+}
+
+/// Abandon the current instruction and return from the run
+pub(crate) fn bail(id: u32) {
+    unsafe { Cpu::from(id).set_run_state(RunState::Bail) };
+}
+
+fn stop(id: u32) {
+    unsafe { Cpu::from(id).set_run_state(RunState::Stop) };
+}
+
+fn skip(id: u32) {
+    let (rip, ins) = CURRENT.get();
+
+    if ins == 0 {
+        set_last_error(
+            bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+            "no instruction is executing to skip",
+        );
+        return stop(id);
+    }
+
+    let len = unsafe { instr_ilen(ins as *const c_void) };
+    restart(id, rip.wrapping_add(len as u64));
+}
+
+/// Carry out a `BOCHSCPU_HOOK_ACTION_*` returned by a hook
+pub(crate) fn act(id: u32, action: u32) {
+    match action {
+        BOCHSCPU_HOOK_ACTION_CONTINUE => (),
+        BOCHSCPU_HOOK_ACTION_STOP => stop(id),
+        BOCHSCPU_HOOK_ACTION_SKIP => skip(id),
+        BOCHSCPU_HOOK_ACTION_REDIRECT => restart(id, unsafe { Cpu::from(id).rip() }),
+        _ => {
+            set_last_error(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("hook returned invalid action {}", action),
+            );
+            stop(id);
+        }
+    }
+}
+
+/// Tracks the executing instruction for hooks which return actions
+///
+/// Must be registered before any `bochscpu_hooks_t`.
+pub(crate) struct ActionHooks;
+
+impl Hooks for ActionHooks {
+    fn before_execution(&mut self, id: u32, ins: *mut c_void) {
+        let rip = unsafe { Cpu::from(id).rip() };
+        CURRENT.set((rip, ins as usize));
+
+        if SKIP_DECODED.get() == ins as usize {
+            SKIP_DECODED.set(0);
+            skip(id);
+        }
+    }
+}
+
+impl bochscpu_hooks_t {
+    /// Copy a caller provided hooks struct
+    ///
+    /// Only the first `struct_size` bytes are read, every hook past them is
+    /// NULL.
+    pub(crate) unsafe fn from_raw(p: *const Self) -> Result<Self, (bochscpu_error_t, String)> {
+        let (size, version) = unsafe { ((*p).struct_size, (*p).version) };
+
+        let header = mem::offset_of!(Self, reset);
+        if size < header || version == 0 || version > BOCHSCPU_HOOKS_VERSION {
+            return Err((
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                format!(
+                    "hooks struct size {} version {} is not valid, expected at least size {} \
+                     and at most version {}",
+                    size, version, header, BOCHSCPU_HOOKS_VERSION
+                ),
+            ));
+        }
+
+        // all NULL is a valid value for every field
+        let mut h: Self = unsafe { mem::zeroed() };
+        let len = size.min(mem::size_of::<Self>());
+        unsafe { ptr::copy_nonoverlapping(p as *const u8, &mut h as *mut Self as *mut u8, len) };
+
+        h.struct_size = mem::size_of::<Self>();
+        h.version = BOCHSCPU_HOOKS_VERSION;
+
+        Ok(h)
+    }
+
+    /// Check if any hook returning an action is set
+    pub(crate) fn has_actions(&self) -> bool {
+        self.before_execution_action.is_some()
+            || self.exception_action.is_some()
+            || self.interrupt_action.is_some()
+            || self.opcode_action.is_some()
+            || self.lin_access_action.is_some()
+            || self.phy_access_action.is_some()
+    }
 }
 
 impl Hooks for bochscpu_hooks_t {
     fn reset(&mut self, id: u32, ty: ResetSource) {
-        self.reset.map(|f| f(self.ctx, id, ty as u32));
+        if let Some(f) = self.reset {
+            f(self.ctx, id, ty.into());
+        }
     }
 
     fn hlt(&mut self, id: u32) {
-        self.hlt.map(|f| f(self.ctx, id));
+        if let Some(f) = self.hlt {
+            f(self.ctx, id);
+        }
     }
 
     fn mwait(&mut self, id: u32, addr: PhyAddress, len: usize, flags: u32) {
-        self.mwait.map(|f| f(self.ctx, id, addr, len, flags));
+        if let Some(f) = self.mwait {
+            f(self.ctx, id, addr, len, flags);
+        }
     }
 
     fn cnear_branch_taken(&mut self, id: u32, branch_pc: Address, new_pc: Address) {
-        self.cnear_branch_taken
-            .map(|f| f(self.ctx, id, branch_pc, new_pc));
+        if let Some(f) = self.cnear_branch_taken {
+            f(self.ctx, id, branch_pc, new_pc);
+        }
     }
 
     fn cnear_branch_not_taken(&mut self, id: u32, pc: Address, new_pc: Address) {
-        self.cnear_branch_not_taken
-            .map(|f| f(self.ctx, id, pc, new_pc));
+        if let Some(f) = self.cnear_branch_not_taken {
+            f(self.ctx, id, pc, new_pc);
+        }
     }
 
     fn ucnear_branch(&mut self, id: u32, what: Branch, branch_pc: Address, new_pc: Address) {
-        self.ucnear_branch
-            .map(|f| f(self.ctx, id, what as u32, branch_pc, new_pc));
+        if let Some(f) = self.ucnear_branch {
+            f(self.ctx, id, what.into(), branch_pc, new_pc);
+        }
     }
 
     fn far_branch(
@@ -164,21 +792,21 @@ impl Hooks for bochscpu_hooks_t {
         branch_pc: (u16, Address),
         new_pc: (u16, Address),
     ) {
-        self.far_branch.map(|f| {
+        if let Some(f) = self.far_branch {
             f(
                 self.ctx,
                 id,
-                what as u32,
+                what.into(),
                 branch_pc.0,
                 branch_pc.1,
                 new_pc.0,
                 new_pc.1,
-            )
-        });
+            );
+        }
     }
 
     fn opcode(&mut self, id: u32, ins: *const c_void, opcode: &[u8], is_32: bool, is_64: bool) {
-        self.opcode.map(|f| {
+        if let Some(f) = self.opcode {
             f(
                 self.ctx,
                 id,
@@ -187,66 +815,473 @@ impl Hooks for bochscpu_hooks_t {
                 opcode.len(),
                 is_32,
                 is_64,
-            )
-        });
+            );
+        }
+
+        if let Some(f) = self.opcode_action {
+            let action = f(
+                self.ctx,
+                id,
+                ins,
+                opcode.as_ptr(),
+                opcode.len(),
+                is_32,
+                is_64,
+            );
+
+            // the instruction has only been decoded, skip it once it executes
+            match action {
+                BOCHSCPU_HOOK_ACTION_SKIP => SKIP_DECODED.set(ins as usize),
+                _ => act(id, action),
+            }
+        }
+    }
+
+    fn interrupt(&mut self, id: u32, vector: u32) {
+        if let Some(f) = self.interrupt {
+            f(self.ctx, id, vector);
+        }
+
+        if let Some(f) = self.interrupt_action {
+            act(id, f(self.ctx, id, vector));
+        }
+    }
+
+    fn exception(&mut self, id: u32, vector: u32, error_code: u32) {
+        if let Some(f) = self.exception {
+            f(self.ctx, id, vector, error_code);
+        }
+
+        if let Some(f) = self.exception_action {
+            act(id, f(self.ctx, id, vector, error_code));
+        }
+    }
+
+    fn hw_interrupt(&mut self, id: u32, vector: u32, pc: (u16, Address)) {
+        if let Some(f) = self.hw_interrupt {
+            f(self.ctx, id, vector, pc.0, pc.1);
+        }
+    }
+
+    fn tlb_cntrl(&mut self, id: u32, what: TlbCntrl, new_cr: Option<PhyAddress>) {
+        let cr = new_cr.unwrap_or_default();
+        if let Some(f) = self.tlb_cntrl {
+            f(self.ctx, id, what.into(), cr);
+        }
+    }
+
+    fn cache_cntrl(&mut self, id: u32, what: CacheCntrl) {
+        if let Some(f) = self.cache_cntrl {
+            f(self.ctx, id, what.into());
+        }
+    }
+
+    fn prefetch_hint(&mut self, id: u32, what: PrefetchHint, seg: u32, off: Address) {
+        if let Some(f) = self.prefetch_hint {
+            f(self.ctx, id, what.into(), seg, off);
+        }
+    }
+
+    fn clflush(&mut self, id: u32, vaddr: Address, paddr: PhyAddress) {
+        if let Some(f) = self.clflush {
+            f(self.ctx, id, vaddr, paddr);
+        }
+    }
+
+    fn before_execution(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(f) = self.before_execution {
+            f(self.ctx, id, ins);
+        }
+
+        if let Some(f) = self.before_execution_action {
+            act(id, f(self.ctx, id, ins));
+        }
+    }
+
+    fn after_execution(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(f) = self.after_execution {
+            f(self.ctx, id, ins);
+        }
+    }
+
+    fn repeat_iteration(&mut self, id: u32, ins: *mut c_void) {
+        if let Some(f) = self.repeat_iteration {
+            f(self.ctx, id, ins);
+        }
+    }
+
+    fn inp(&mut self, addr: u16, len: usize) {
+        if let Some(f) = self.inp {
+            f(self.ctx, addr, len);
+        }
+    }
+
+    fn inp2(&mut self, addr: u16, len: usize, val: u32) {
+        if let Some(f) = self.inp2 {
+            f(self.ctx, addr, len, val);
+        }
+    }
+
+    fn outp(&mut self, addr: u16, len: usize, val: u32) {
+        if let Some(f) = self.outp {
+            f(self.ctx, addr, len, val);
+        }
+    }
+
+    fn lin_access(
+        &mut self,
+        id: u32,
+        vaddr: Address,
+        paddr: Address,
+        len: usize,
+        memty: MemType,
+        rw: MemAccess,
+    ) {
+        if let Some(f) = self.lin_access {
+            f(self.ctx, id, vaddr, paddr, len, memty.into(), rw.into());
+        }
+
+        if let Some(f) = self.lin_access_action {
+            act(
+                id,
+                f(self.ctx, id, vaddr, paddr, len, memty.into(), rw.into()),
+            );
+        }
+    }
+
+    fn phy_access(
+        &mut self,
+        id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        memty: MemType,
+        rw: MemAccess,
+    ) {
+        if let Some(f) = self.phy_access {
+            f(self.ctx, id, paddr, len, memty.into(), rw.into());
+        }
+
+        if let Some(f) = self.phy_access_action {
+            act(id, f(self.ctx, id, paddr, len, memty.into(), rw.into()));
+        }
+    }
+
+    fn wrmsr(&mut self, id: u32, msr: u32, val: u64) {
+        if let Some(f) = self.wrmsr {
+            f(self.ctx, id, msr, val);
+        }
+    }
+
+    fn vmexit(&mut self, id: u32, reason: u32, qualification: u64) {
+        if let Some(f) = self.vmexit {
+            f(self.ctx, id, reason, qualification);
+        }
+    }
+}
+
+/// Hook categories user hooks are dispatched for
+static CATEGORIES: AtomicU32 = AtomicU32::new(BOCHSCPU_HOOK_CAT_ALL);
+
+#[derive(Clone, Copy)]
+enum Event {
+    Reset,
+    Hlt,
+    Mwait,
+    CnearBranchTaken,
+    CnearBranchNotTaken,
+    UcnearBranch,
+    FarBranch,
+    Opcode,
+    Interrupt,
+    Exception,
+    HwInterrupt,
+    TlbCntrl,
+    CacheCntrl,
+    PrefetchHint,
+    Clflush,
+    BeforeExecution,
+    AfterExecution,
+    RepeatIteration,
+    Inp,
+    Inp2,
+    Outp,
+    LinAccess,
+    PhyAccess,
+    Wrmsr,
+    Vmexit,
+}
+
+impl Event {
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn category(self) -> u32 {
+        use Event::*;
+
+        match self {
+            Reset | Hlt | Mwait => BOCHSCPU_HOOK_CAT_CONTROL,
+            CnearBranchTaken | CnearBranchNotTaken | UcnearBranch | FarBranch => {
+                BOCHSCPU_HOOK_CAT_BRANCH
+            }
+            Opcode => BOCHSCPU_HOOK_CAT_OPCODE,
+            Interrupt | Exception | HwInterrupt => BOCHSCPU_HOOK_CAT_INTERRUPT,
+            TlbCntrl | CacheCntrl | PrefetchHint | Clflush => BOCHSCPU_HOOK_CAT_CACHE,
+            BeforeExecution | AfterExecution | RepeatIteration => BOCHSCPU_HOOK_CAT_EXECUTION,
+            Inp | Inp2 | Outp => BOCHSCPU_HOOK_CAT_IO,
+            LinAccess | PhyAccess => BOCHSCPU_HOOK_CAT_MEMORY,
+            Wrmsr | Vmexit => BOCHSCPU_HOOK_CAT_SYSTEM,
+        }
+    }
+}
+
+impl bochscpu_hooks_t {
+    /// Mask of the events this struct has a hook for
+    fn events(&self) -> u32 {
+        use Event::*;
+
+        [
+            (self.reset.is_some(), Reset),
+            (self.hlt.is_some(), Hlt),
+            (self.mwait.is_some(), Mwait),
+            (self.cnear_branch_taken.is_some(), CnearBranchTaken),
+            (self.cnear_branch_not_taken.is_some(), CnearBranchNotTaken),
+            (self.ucnear_branch.is_some(), UcnearBranch),
+            (self.far_branch.is_some(), FarBranch),
+            (
+                self.opcode.is_some() || self.opcode_action.is_some(),
+                Opcode,
+            ),
+            (
+                self.interrupt.is_some() || self.interrupt_action.is_some(),
+                Interrupt,
+            ),
+            (
+                self.exception.is_some() || self.exception_action.is_some(),
+                Exception,
+            ),
+            (self.hw_interrupt.is_some(), HwInterrupt),
+            (self.tlb_cntrl.is_some(), TlbCntrl),
+            (self.cache_cntrl.is_some(), CacheCntrl),
+            (self.prefetch_hint.is_some(), PrefetchHint),
+            (self.clflush.is_some(), Clflush),
+            (
+                self.before_execution.is_some() || self.before_execution_action.is_some(),
+                BeforeExecution,
+            ),
+            (self.after_execution.is_some(), AfterExecution),
+            (self.repeat_iteration.is_some(), RepeatIteration),
+            (self.inp.is_some(), Inp),
+            (self.inp2.is_some(), Inp2),
+            (self.outp.is_some(), Outp),
+            (
+                self.lin_access.is_some() || self.lin_access_action.is_some(),
+                LinAccess,
+            ),
+            (
+                self.phy_access.is_some() || self.phy_access_action.is_some(),
+                PhyAccess,
+            ),
+            (self.wrmsr.is_some(), Wrmsr),
+            (self.vmexit.is_some(), Vmexit),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |mask, (_, e)| mask | e.bit())
+    }
+}
+
+/// Dispatches events to user hooks
+///
+/// Events no struct has a hook for, or whose category is disabled with
+/// `bochscpu_hooks_enable()`, are dropped without visiting the structs. The
+/// core still calls through the `Hooks` trait for every event once this is
+/// registered, so this saves the per-struct calls, not the dispatch itself.
+pub(crate) struct HookDispatch {
+    hooks: Vec<bochscpu_hooks_t>,
+    events: u32,
+}
+
+impl HookDispatch {
+    pub(crate) fn new(hooks: Vec<bochscpu_hooks_t>) -> Self {
+        let events = hooks.iter().fold(0, |mask, h| mask | h.events());
+
+        Self { hooks, events }
+    }
+
+    /// Check if any struct has a hook at all
+    pub(crate) fn is_empty(&self) -> bool {
+        self.events == 0
+    }
+
+    pub(crate) fn has_actions(&self) -> bool {
+        self.hooks.iter().any(|h| h.has_actions())
+    }
+
+    fn wants(&self, e: Event) -> bool {
+        self.events & e.bit() != 0 && CATEGORIES.load(Ordering::Relaxed) & e.category() != 0
+    }
+}
+
+impl Hooks for HookDispatch {
+    fn reset(&mut self, id: u32, ty: ResetSource) {
+        if self.wants(Event::Reset) {
+            self.hooks.iter_mut().for_each(|h| h.reset(id, ty));
+        }
+    }
+
+    fn hlt(&mut self, id: u32) {
+        if self.wants(Event::Hlt) {
+            self.hooks.iter_mut().for_each(|h| h.hlt(id));
+        }
+    }
+
+    fn mwait(&mut self, id: u32, addr: PhyAddress, len: usize, flags: u32) {
+        if self.wants(Event::Mwait) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.mwait(id, addr, len, flags));
+        }
+    }
+
+    fn cnear_branch_taken(&mut self, id: u32, branch_pc: Address, new_pc: Address) {
+        if self.wants(Event::CnearBranchTaken) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.cnear_branch_taken(id, branch_pc, new_pc));
+        }
+    }
+
+    fn cnear_branch_not_taken(&mut self, id: u32, pc: Address, new_pc: Address) {
+        if self.wants(Event::CnearBranchNotTaken) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.cnear_branch_not_taken(id, pc, new_pc));
+        }
+    }
+
+    fn ucnear_branch(&mut self, id: u32, what: Branch, branch_pc: Address, new_pc: Address) {
+        if self.wants(Event::UcnearBranch) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.ucnear_branch(id, what, branch_pc, new_pc));
+        }
+    }
+
+    fn far_branch(
+        &mut self,
+        id: u32,
+        what: Branch,
+        branch_pc: (u16, Address),
+        new_pc: (u16, Address),
+    ) {
+        if self.wants(Event::FarBranch) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.far_branch(id, what, branch_pc, new_pc));
+        }
+    }
+
+    fn opcode(&mut self, id: u32, ins: *const c_void, opcode: &[u8], is_32: bool, is_64: bool) {
+        if self.wants(Event::Opcode) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.opcode(id, ins, opcode, is_32, is_64));
+        }
     }
 
     fn interrupt(&mut self, id: u32, vector: u32) {
-        self.interrupt.map(|f| f(self.ctx, id, vector));
+        if self.wants(Event::Interrupt) {
+            self.hooks.iter_mut().for_each(|h| h.interrupt(id, vector));
+        }
     }
 
     fn exception(&mut self, id: u32, vector: u32, error_code: u32) {
-        self.exception.map(|f| f(self.ctx, id, vector, error_code));
+        if self.wants(Event::Exception) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.exception(id, vector, error_code));
+        }
     }
 
     fn hw_interrupt(&mut self, id: u32, vector: u32, pc: (u16, Address)) {
-        self.hw_interrupt
-            .map(|f| f(self.ctx, id, vector, pc.0, pc.1));
+        if self.wants(Event::HwInterrupt) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.hw_interrupt(id, vector, pc));
+        }
     }
 
     fn tlb_cntrl(&mut self, id: u32, what: TlbCntrl, new_cr: Option<PhyAddress>) {
-        let cr = match new_cr {
-            Some(v) => v,
-            None => 0,
-        };
-        self.tlb_cntrl.map(|f| f(self.ctx, id, what as u32, cr));
+        if self.wants(Event::TlbCntrl) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.tlb_cntrl(id, what, new_cr));
+        }
     }
 
     fn cache_cntrl(&mut self, id: u32, what: CacheCntrl) {
-        self.cache_cntrl.map(|f| f(self.ctx, id, what as u32));
+        if self.wants(Event::CacheCntrl) {
+            self.hooks.iter_mut().for_each(|h| h.cache_cntrl(id, what));
+        }
     }
 
     fn prefetch_hint(&mut self, id: u32, what: PrefetchHint, seg: u32, off: Address) {
-        self.prefetch_hint
-            .map(|f| f(self.ctx, id, what as u32, seg, off));
+        if self.wants(Event::PrefetchHint) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.prefetch_hint(id, what, seg, off));
+        }
     }
 
     fn clflush(&mut self, id: u32, vaddr: Address, paddr: PhyAddress) {
-        self.clflush.map(|f| f(self.ctx, id, vaddr, paddr));
+        if self.wants(Event::Clflush) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.clflush(id, vaddr, paddr));
+        }
     }
 
     fn before_execution(&mut self, id: u32, ins: *mut c_void) {
-        self.before_execution.map(|f| f(self.ctx, id, ins));
+        if self.wants(Event::BeforeExecution) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.before_execution(id, ins));
+        }
     }
 
     fn after_execution(&mut self, id: u32, ins: *mut c_void) {
-        self.after_execution.map(|f| f(self.ctx, id, ins));
+        if self.wants(Event::AfterExecution) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.after_execution(id, ins));
+        }
     }
 
     fn repeat_iteration(&mut self, id: u32, ins: *mut c_void) {
-        self.repeat_iteration.map(|f| f(self.ctx, id, ins));
+        if self.wants(Event::RepeatIteration) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.repeat_iteration(id, ins));
+        }
     }
 
     fn inp(&mut self, addr: u16, len: usize) {
-        self.inp.map(|f| f(self.ctx, addr, len));
+        if self.wants(Event::Inp) {
+            self.hooks.iter_mut().for_each(|h| h.inp(addr, len));
+        }
     }
 
     fn inp2(&mut self, addr: u16, len: usize, val: u32) {
-        self.inp2.map(|f| f(self.ctx, addr, len, val));
+        if self.wants(Event::Inp2) {
+            self.hooks.iter_mut().for_each(|h| h.inp2(addr, len, val));
+        }
     }
 
     fn outp(&mut self, addr: u16, len: usize, val: u32) {
-        self.outp.map(|f| f(self.ctx, addr, len, val));
+        if self.wants(Event::Outp) {
+            self.hooks.iter_mut().for_each(|h| h.outp(addr, len, val));
+        }
     }
 
     fn lin_access(
@@ -258,8 +1293,11 @@ impl Hooks for bochscpu_hooks_t {
         memty: MemType,
         rw: MemAccess,
     ) {
-        self.lin_access
-            .map(|f| f(self.ctx, id, vaddr, paddr, len, memty as u32, rw as u32));
+        if self.wants(Event::LinAccess) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.lin_access(id, vaddr, paddr, len, memty, rw));
+        }
     }
 
     fn phy_access(
@@ -270,15 +1308,138 @@ impl Hooks for bochscpu_hooks_t {
         memty: MemType,
         rw: MemAccess,
     ) {
-        self.phy_access
-            .map(|f| f(self.ctx, id, paddr, len, memty as u32, rw as u32));
+        if self.wants(Event::PhyAccess) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.phy_access(id, paddr, len, memty, rw));
+        }
     }
 
     fn wrmsr(&mut self, id: u32, msr: u32, val: u64) {
-        self.wrmsr.map(|f| f(self.ctx, id, msr, val));
+        if self.wants(Event::Wrmsr) {
+            self.hooks.iter_mut().for_each(|h| h.wrmsr(id, msr, val));
+        }
     }
 
     fn vmexit(&mut self, id: u32, reason: u32, qualification: u64) {
-        self.vmexit.map(|f| f(self.ctx, id, reason, qualification));
+        if self.wants(Event::Vmexit) {
+            self.hooks
+                .iter_mut()
+                .for_each(|h| h.vmexit(id, reason, qualification));
+        }
+    }
+}
+
+/// Enable or disable hook categories
+///
+/// `categories` is a combination of the `BOCHSCPU_HOOK_CAT_*` constants.
+/// While a category is disabled its hooks in every `bochscpu_hooks_t` are not
+/// called, including their `*_action` variants. This may be called from a
+/// hook and takes effect for the next event. Every category starts enabled.
+///
+/// The core still reports every event to the library while any hook is set,
+/// so disabling a category saves calling the hooks, not the instrumentation
+/// overhead of the core itself.
+///
+/// Breakpoints, watchpoints and the other library features are not affected.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if `categories` has unknown bits set.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_hooks_enable(categories: u32, enable: bool) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || {
+        if categories & !BOCHSCPU_HOOK_CAT_ALL != 0 {
+            return fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("invalid hook categories {:#x}", categories),
+            );
+        }
+
+        if enable {
+            CATEGORIES.fetch_or(categories, Ordering::Relaxed);
+        } else {
+            CATEGORIES.fetch_and(!categories, Ordering::Relaxed);
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Get the enabled hook categories
+///
+/// # Returns
+///
+/// A combination of the `BOCHSCPU_HOOK_CAT_*` constants.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_hooks_enabled() -> u32 {
+    guard_or(0, || CATEGORIES.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn hlt(_ctx: *mut c_void, _id: u32) {}
+
+    extern "C" fn action(_ctx: *mut c_void, _id: u32, _ins: *mut c_void) -> u32 {
+        BOCHSCPU_HOOK_ACTION_CONTINUE
+    }
+
+    fn hooks(struct_size: usize, version: u32) -> bochscpu_hooks_t {
+        let mut h: bochscpu_hooks_t = unsafe { mem::zeroed() };
+
+        h.struct_size = struct_size;
+        h.version = version;
+        h.hlt = Some(hlt);
+        h.before_execution_action = Some(action);
+
+        h
+    }
+
+    #[test]
+    fn struct_size() {
+        let full = mem::size_of::<bochscpu_hooks_t>();
+        let actions = mem::offset_of!(bochscpu_hooks_t, before_execution_action);
+
+        let h = unsafe { bochscpu_hooks_t::from_raw(&hooks(full, BOCHSCPU_HOOKS_VERSION)) };
+        let h = h.unwrap();
+        assert!(h.hlt.is_some() && h.has_actions());
+
+        // hooks past the declared size are NULL
+        let h = unsafe { bochscpu_hooks_t::from_raw(&hooks(actions, BOCHSCPU_HOOKS_VERSION)) };
+        let h = h.unwrap();
+        assert!(h.hlt.is_some() && !h.has_actions());
+        assert_eq!((h.struct_size, h.version), (full, BOCHSCPU_HOOKS_VERSION));
+
+        let header = mem::offset_of!(bochscpu_hooks_t, reset);
+        let h = unsafe { bochscpu_hooks_t::from_raw(&hooks(header, BOCHSCPU_HOOKS_VERSION)) };
+        assert!(h.unwrap().hlt.is_none());
+
+        for (size, version) in [
+            (header - 1, BOCHSCPU_HOOKS_VERSION),
+            (full, 0),
+            (full, BOCHSCPU_HOOKS_VERSION + 1),
+        ] {
+            let err = unsafe { bochscpu_hooks_t::from_raw(&hooks(size, version)) }.unwrap_err();
+            assert_eq!(err.0, bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT);
+        }
+    }
+
+    #[test]
+    fn skip_current() {
+        let id = 0x305;
+
+        // nothing has executed on this thread yet
+        act(id, BOCHSCPU_HOOK_ACTION_SKIP);
+        assert_eq!(take_restart(), None);
+
+        unsafe { Cpu::from(id).set_rip(0x1000) };
+        let ins = 0x10 as *mut c_void;
+        ActionHooks.before_execution(id, ins);
+
+        act(id, BOCHSCPU_HOOK_ACTION_SKIP);
+        let len = unsafe { instr_ilen(ins) };
+        assert_eq!(take_restart(), Some(0x1000 + len as u64));
     }
 }
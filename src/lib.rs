@@ -8,16 +8,40 @@ extern crate static_assertions;
 ///   using the guest page tables and a particular cr3.
 /// - HVA: Host Virtual Address, an address valid in the emulator itself, NOT
 ///   the guest
+mod bp;
 mod cpu;
+mod dirty;
+mod error;
+mod file;
 mod hook;
 mod instr;
 mod log;
 mod mem;
+mod mmio;
 mod opcode;
+mod paging;
+mod pt;
+mod snapshot;
+mod state;
+#[cfg(test)]
+mod testutil;
+mod virt;
+mod watch;
 
+pub use crate::bp::*;
 pub use crate::cpu::*;
+pub use crate::dirty::*;
+pub use crate::error::*;
+pub use crate::file::*;
 pub use crate::hook::*;
 pub use crate::instr::*;
 pub use crate::log::*;
 pub use crate::mem::*;
+pub use crate::mmio::*;
 pub use crate::opcode::*;
+pub use crate::paging::*;
+pub use crate::pt::*;
+pub use crate::snapshot::*;
+pub use crate::state::*;
+pub use crate::virt::*;
+pub use crate::watch::*;
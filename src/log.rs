@@ -1,4 +1,33 @@
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or};
+
+/// Set the log verbosity
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the logger has already been initialized.
 #[unsafe(no_mangle)]
-pub extern "C" fn bochscpu_log_set_level(level: usize) {
-    stderrlog::new().verbosity(level).init().unwrap();
+pub extern "C" fn bochscpu_log_set_level(level: usize) -> bochscpu_status_t {
+    guard_or(
+        bochscpu_status_t::BOCHSCPU_STATUS_PANIC,
+        || match stderrlog::new().verbosity(level).init() {
+            Ok(()) => bochscpu_status_t::BOCHSCPU_STATUS_OK,
+            Err(e) => fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("logger is already initialized: {}", e),
+            ),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_level_twice() {
+        bochscpu_log_set_level(0);
+
+        let status = bochscpu_log_set_level(0);
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+    }
 }
@@ -1,25 +1,404 @@
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_void;
+use std::ptr;
 use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
+use bochscpu::hook::*;
 use bochscpu::mem::*;
+use bochscpu::{Address, PhyAddress};
+
+use crate::cpu;
+use crate::dirty;
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard, guard_or, set_last_error};
+use crate::file;
+use crate::hook;
+use crate::hook::bochscpu_hook_mem_t::{self, *};
+use crate::paging::{
+    self, Fault, PAGE_SIZE, bochscpu_mem_access_t, bochscpu_mem_fault_t, bochscpu_mem_region_t,
+    bochscpu_mem_walk_t,
+};
+
+/// A GPA to HVA mapping
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct bochscpu_mem_page_t {
+    pub gpa: u64,
+    pub hva: *mut u8,
+}
+
+/// Every page added with `bochscpu_mem_page_insert()`, GPA -> HVA
+static PAGES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// Lock the GPA -> HVA map of inserted pages
+///
+/// The lock must not be held across calls which may fault in a page, as the
+/// missing page handler will re-enter `bochscpu_mem_page_insert()`.
+pub(crate) fn mapped_pages() -> MutexGuard<'static, BTreeMap<u64, usize>> {
+    PAGES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Pages allocated by the library rather than provided by the caller
+static OWNED: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+fn owned() -> MutexGuard<'static, BTreeSet<usize>> {
+    OWNED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Pages inserted for `BOCHSCPU_MEM_MISSING_UNRESOLVED`, which read as all ones
+static OPEN_BUS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+// size of OPEN_BUS, checked on every guest write without taking the lock
+static OPEN_BUS_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn open_bus() -> MutexGuard<'static, BTreeSet<u64>> {
+    OPEN_BUS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Undo writes to open bus pages in `[gpa, gpa + len)`
+pub(crate) fn discard_writes(gpa: u64, len: usize) {
+    if OPEN_BUS_PAGES.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let open = open_bus();
+    for (pa, _, _) in paging::page_chunks(gpa, len) {
+        let pa = pa & !(PAGE_SIZE - 1);
+
+        if open.contains(&pa)
+            && let Some(&hva) = mapped_pages().get(&pa)
+        {
+            unsafe { ptr::write_bytes(hva as *mut u8, 0xff, PAGE_SIZE as usize) };
+        }
+    }
+}
+
+/// Record a write made by the library to `[gpa, gpa + len)`
+pub(crate) fn wrote(gpa: u64, len: usize) {
+    dirty::mark(gpa, len);
+    discard_writes(gpa, len);
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap()
+}
+
+/// Back a GPA with a page allocated by the library, filled with `fill`
+///
+/// The page is freed when it is removed or replaced.
+pub(crate) unsafe fn insert_owned(gpa: u64, fill: u8) {
+    let hva = unsafe { alloc::alloc(page_layout()) };
+    if hva.is_null() {
+        alloc::handle_alloc_error(page_layout());
+    }
+
+    unsafe { ptr::write_bytes(hva, fill, PAGE_SIZE as usize) };
+    owned().insert(hva as usize);
+
+    unsafe { insert(gpa & !(PAGE_SIZE - 1), hva) };
+}
+
+/// Number of GPAs each inserted HVA is mapped at
+static REFS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn refs() -> MutexGuard<'static, BTreeMap<usize, usize>> {
+    REFS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn retain(hva: usize) {
+    *refs().entry(hva).or_default() += 1;
+}
+
+/// Drop a reference to an HVA which is no longer mapped at some GPA
+///
+/// Once no GPA maps it anymore, any memory owned by the library which backed
+/// it is freed.
+fn release(hva: usize) {
+    {
+        let mut refs = refs();
+        let Some(n) = refs.get_mut(&hva) else {
+            return;
+        };
+
+        *n -= 1;
+        if *n != 0 {
+            return;
+        }
+
+        refs.remove(&hva);
+    }
+
+    file::release(hva);
+
+    if owned().remove(&hva) {
+        unsafe { alloc::dealloc(hva as *mut u8, page_layout()) };
+    }
+}
+
+pub(crate) fn virt_error(kind: bochscpu_error_t, cr3: u64, gva: u64) {
+    set_last_error(
+        kind,
+        &format!(
+            "{} translating gva {:#x} with cr3 {:#x}",
+            kind.description(),
+            gva,
+            cr3
+        ),
+    );
+}
+
+unsafe fn access_error(
+    (kind, f): Fault,
+    cr3: u64,
+    gva: u64,
+    fault: *mut bochscpu_mem_fault_t,
+) -> bochscpu_status_t {
+    let Some(f) = f else {
+        virt_error(kind, cr3, gva);
+        return bochscpu_status_t::BOCHSCPU_STATUS_ERROR;
+    };
+
+    if !fault.is_null() {
+        unsafe { *fault = f };
+    }
+
+    fail(
+        kind,
+        &format!(
+            "{} translating gva {:#x} with cr3 {:#x}, vector {} error code {:#x}",
+            kind.description(),
+            f.address,
+            cr3,
+            f.vector,
+            f.error_code
+        ),
+    )
+}
+
+fn bad_access(access: u32, allowed: &[bochscpu_hook_mem_t]) -> Option<bochscpu_status_t> {
+    if allowed.iter().any(|&k| k as u32 == access) {
+        return None;
+    }
+
+    Some(fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+        &format!("access kind {} is not valid here", access),
+    ))
+}
+
+pub(crate) unsafe fn insert(gpa: u64, hva: *mut u8) {
+    unsafe { page_insert(gpa, hva) };
+
+    let old = mapped_pages().insert(gpa, hva as usize);
+    dirty::mark_remapped(gpa);
+    forget_open_bus(gpa);
+
+    if old != Some(hva as usize) {
+        retain(hva as usize);
+
+        if let Some(old) = old {
+            release(old);
+        }
+    }
+}
+
+pub(crate) unsafe fn remove(gpa: u64) {
+    unsafe { page_remove(gpa) };
+
+    let old = mapped_pages().remove(&gpa);
+    if let Some(old) = old {
+        dirty::mark_remapped(gpa);
+        forget_open_bus(gpa);
+        release(old);
+    }
+}
+
+fn forget_open_bus(gpa: u64) {
+    if OPEN_BUS_PAGES.load(Ordering::Relaxed) != 0 {
+        let mut open = open_bus();
+
+        open.remove(&gpa);
+        OPEN_BUS_PAGES.store(open.len(), Ordering::Relaxed);
+    }
+}
+
+unsafe fn insert_open_bus(gpa: u64) {
+    let gpa = gpa & !(PAGE_SIZE - 1);
+    unsafe { insert_owned(gpa, 0xff) };
+
+    let mut open = open_bus();
+    open.insert(gpa);
+    OPEN_BUS_PAGES.store(open.len(), Ordering::Relaxed);
+}
 
 /// Add GPA mapping to HVA
 ///
 /// If the GPA was already mapped, this replaces the existing mapping
 ///
-/// # Panics
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_UNALIGNED` if the GPA or HVA
+/// is not page aligned, in which case no mapping is added.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_page_insert(gpa: u64, hva: *mut u8) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        if !aligned(gpa, hva) {
+            return unaligned(gpa, hva);
+        }
+
+        insert(gpa, hva);
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+fn aligned(gpa: u64, hva: *mut u8) -> bool {
+    (gpa | hva as u64) & (PAGE_SIZE - 1) == 0
+}
+
+fn unaligned(gpa: u64, hva: *mut u8) -> bochscpu_status_t {
+    fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_UNALIGNED,
+        &format!("gpa {:#x} -> hva {:p} is not page aligned", gpa, hva),
+    )
+}
+
+/// Add many GPA mappings
+///
+/// Equivalent to calling `bochscpu_mem_page_insert()` on each of the `len`
+/// entries in `pages`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_UNALIGNED` if any GPA or HVA
+/// is not page aligned, in which case no mappings are added.
+///
+/// # Safety
 ///
-/// Panics if the added page is not page aligned.
+/// `pages` must be valid for reads of `len` elements, and every HVA must stay
+/// valid until its page is removed.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_mem_page_insert(gpa: u64, hva: *mut u8) {
-    unsafe { page_insert(gpa, hva) }
+pub unsafe extern "C" fn bochscpu_mem_page_insert_many(
+    pages: *const bochscpu_mem_page_t,
+    len: usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        if len == 0 {
+            return bochscpu_status_t::BOCHSCPU_STATUS_OK;
+        }
+
+        let pages = slice::from_raw_parts(pages, len);
+
+        if let Some(p) = pages.iter().find(|p| !aligned(p.gpa, p.hva)) {
+            return unaligned(p.gpa, p.hva);
+        }
+
+        for p in pages {
+            insert(p.gpa, p.hva);
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
 }
 
 /// Delete GPA mapping
 ///
 /// If the GPA is not valid, this is a no-op.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_mem_page_remove(gpa: u64) {
-    unsafe { page_remove(gpa) }
+pub unsafe extern "C" fn bochscpu_mem_page_remove(gpa: u64) -> bochscpu_status_t {
+    guard(|| unsafe { remove(gpa) })
+}
+
+/// Delete many GPA mappings
+///
+/// Equivalent to calling `bochscpu_mem_page_remove()` on each of the `len`
+/// GPAs in `gpas`.
+///
+/// # Safety
+///
+/// `gpas` must be valid for reads of `len` elements.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_page_remove_many(
+    gpas: *const u64,
+    len: usize,
+) -> bochscpu_status_t {
+    guard(|| unsafe {
+        if len == 0 {
+            return;
+        }
+
+        for &gpa in slice::from_raw_parts(gpas, len) {
+            remove(gpa);
+        }
+    })
+}
+
+/// Delete every GPA mapping
+///
+/// # Safety
+///
+/// Must not be called while a cpu is running.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_page_clear() -> bochscpu_status_t {
+    guard(|| unsafe {
+        let gpas: Vec<u64> = mapped_pages().keys().copied().collect();
+
+        for gpa in gpas {
+            remove(gpa);
+        }
+    })
+}
+
+/// Get the number of mapped GPAs
+///
+/// Open bus pages inserted for `BOCHSCPU_MEM_MISSING_UNRESOLVED` are not
+/// counted.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_mem_page_count() -> usize {
+    guard_or(0, || {
+        let open = open_bus().len();
+        mapped_pages().len() - open
+    })
+}
+
+/// Enumerate GPA mappings
+///
+/// Calls `cb` with `ctx` for every mapped page, in ascending GPA order. The
+/// enumeration stops early if `cb` returns false.
+///
+/// The mappings are captured before the first call to `cb`, so `cb` may
+/// insert or remove pages. Open bus pages inserted for
+/// `BOCHSCPU_MEM_MISSING_UNRESOLVED` are skipped.
+///
+/// # Safety
+///
+/// `cb` must be safe to call with `ctx`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_page_enumerate(
+    cb: extern "C" fn(ctx: *mut c_void, gpa: u64, hva: *mut u8) -> bool,
+    ctx: *mut c_void,
+) -> bochscpu_status_t {
+    guard(|| {
+        let pages: Vec<(u64, usize)> = {
+            let open = open_bus();
+            let pages = mapped_pages();
+
+            pages
+                .iter()
+                .filter(|(g, _)| !open.contains(g))
+                .map(|(&g, &h)| (g, h))
+                .collect()
+        };
+
+        for (gpa, hva) in pages {
+            if !cb(ctx, gpa, hva as *mut u8) {
+                break;
+            }
+        }
+    })
 }
 
 /// Install a physical page fault handler
@@ -35,24 +414,453 @@ pub unsafe extern "C" fn bochscpu_mem_page_remove(gpa: u64) {
 /// # Note
 ///
 /// This is a global singleton, and installing a new physical page fault
-/// handler will overwrite the existing handler.
+/// handler will overwrite the existing handler, including one installed with
+/// `bochscpu_mem_missing_page_ctx()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_missing_page(
+    handler: extern "C" fn(gpa: u64),
+) -> bochscpu_status_t {
+    guard(|| unsafe { install(Some(Handler::Plain(handler))) })
+}
+
+/// Outcome of a missing page handler installed with
+/// `bochscpu_mem_missing_page_ctx()`
+///
+/// Handlers return these as a `uint32_t`, any other value is treated as
+/// `BOCHSCPU_MEM_MISSING_ABORT`.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_mem_missing_t {
+    /// The handler inserted a page for the GPA
+    BOCHSCPU_MEM_MISSING_RESOLVED = 0,
+    /// The GPA has no backing memory, treat it as open bus
+    BOCHSCPU_MEM_MISSING_UNRESOLVED = 1,
+    /// Stop emulation
+    BOCHSCPU_MEM_MISSING_ABORT = 2,
+}
+
+impl bochscpu_mem_missing_t {
+    fn from_raw(v: u32) -> Option<Self> {
+        use bochscpu_mem_missing_t::*;
+
+        [
+            BOCHSCPU_MEM_MISSING_RESOLVED,
+            BOCHSCPU_MEM_MISSING_UNRESOLVED,
+            BOCHSCPU_MEM_MISSING_ABORT,
+        ]
+        .into_iter()
+        .find(|&k| k as u32 == v)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Handler {
+    Plain(extern "C" fn(gpa: u64)),
+    Ctx(extern "C" fn(ctx: *mut c_void, gpa: u64) -> u32, usize),
+}
+
+/// The installed missing page handler, the core calls through this as well
+static HANDLER: Mutex<Option<Handler>> = Mutex::new(None);
+
+fn handler() -> MutexGuard<'static, Option<Handler>> {
+    HANDLER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// a handler installed with bochscpu_mem_missing_page_ctx() may abort
+static ABORTABLE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // a handler aborted and the cpu has not bailed out of the access yet
+    static ABORT_PENDING: Cell<bool> = const { Cell::new(false) };
+    // a handler aborted during the current run
+    static ABORTED: Cell<bool> = const { Cell::new(false) };
+    // pages inserted so the core can finish an aborted access, GPA and HVA
+    static SCRATCH: RefCell<Vec<(u64, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+unsafe fn install(h: Option<Handler>) {
+    *handler() = h;
+    ABORTABLE.store(matches!(h, Some(Handler::Ctx(..))), Ordering::Relaxed);
+
+    unsafe {
+        missing_page(|gpa| {
+            if let Err((kind, msg)) = call_handler(gpa) {
+                set_last_error(kind, &msg);
+                abort(gpa);
+            }
+        })
+    };
+}
+
+/// Call the missing page handler for `gpa`
+///
+/// Fails if a handler installed with `bochscpu_mem_missing_page_ctx()`
+/// aborted the access.
+fn call_handler(gpa: u64) -> Result<(), (bochscpu_error_t, String)> {
+    use bochscpu_mem_missing_t::*;
+
+    // the handler may insert pages, so do not hold the lock while it runs
+    let h = *handler();
+
+    let (f, ctx) = match h {
+        None => return Ok(()),
+        Some(Handler::Plain(f)) => {
+            f(gpa);
+            return Ok(());
+        }
+        Some(Handler::Ctx(f, ctx)) => (f, ctx),
+    };
+
+    let code = f(ctx as _, gpa);
+
+    match bochscpu_mem_missing_t::from_raw(code) {
+        Some(BOCHSCPU_MEM_MISSING_RESOLVED) => Ok(()),
+        Some(BOCHSCPU_MEM_MISSING_UNRESOLVED) => {
+            unsafe { insert_open_bus(gpa) };
+            Ok(())
+        }
+        Some(BOCHSCPU_MEM_MISSING_ABORT) => Err((
+            bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT,
+            format!("missing page handler aborted on gpa {:#x}", gpa),
+        )),
+        None => Err((
+            bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+            format!(
+                "missing page handler returned invalid code {} for gpa {:#x}",
+                code, gpa
+            ),
+        )),
+    }
+}
+
+/// Run the missing page handler for every page in `[gpa, gpa + len)` which
+/// is not present, ahead of an access made by the library
+///
+/// Fails if the handler aborted. A page the handler did not insert is not an
+/// error here, the access itself decides how to treat it.
+pub(crate) fn fault_in(gpa: u64, len: usize) -> Result<(), (bochscpu_error_t, String)> {
+    for (pa, _, _) in paging::page_chunks(gpa, len) {
+        let present = mapped_pages().contains_key(&(pa & !(PAGE_SIZE - 1)));
+
+        if !present {
+            call_handler(pa)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `fault_in()`, recording the failure and returning the status to report
+pub(crate) fn fault_in_status(gpa: u64, len: usize) -> Option<bochscpu_status_t> {
+    fault_in(gpa, len).err().map(|(kind, msg)| fail(kind, &msg))
+}
+
+/// Whether `MissingHooks` has anything to do during a run
+pub(crate) fn needs_hooks() -> bool {
+    ABORTABLE.load(Ordering::Relaxed) || OPEN_BUS_PAGES.load(Ordering::Relaxed) != 0
+}
+
+unsafe fn abort(gpa: u64) {
+    // library accesses fault pages in first and report the abort themselves,
+    // so this is only reached from the core outside of a run by mistake
+    if cpu::running().is_none() {
+        return;
+    }
+
+    // the core expects a page once the handler returns, the cpu bails out
+    // before the instruction retires and the page is removed after the run
+    unsafe { insert_owned(gpa, 0xff) };
+    let hva = mapped_pages()[&(gpa & !(PAGE_SIZE - 1))];
+
+    SCRATCH.with(|s| s.borrow_mut().push((gpa & !(PAGE_SIZE - 1), hva)));
+    ABORT_PENDING.set(true);
+    ABORTED.set(true);
+}
+
+/// Clean up after a run on this thread
+///
+/// Removes the pages inserted for aborted accesses, and returns true if a
+/// handler aborted. The reason was recorded as the last error by then.
+pub(crate) fn finish_run() -> bool {
+    let scratch = SCRATCH.with(|s| s.take());
+    for (gpa, hva) in scratch {
+        if mapped_pages().get(&gpa) == Some(&hva) {
+            unsafe { remove(gpa) };
+        }
+    }
+
+    ABORT_PENDING.set(false);
+    ABORTED.replace(false)
+}
+
+/// Bails out of the instruction whose access a missing page handler aborted,
+/// and discards guest writes to open bus pages
+///
+/// Loads are reported after the data is read and stores before it lands, so
+/// either way the first hook after the handler returns comes before the
+/// instruction retires. Fetches are caught before the instruction executes.
+#[derive(Default)]
+pub(crate) struct MissingHooks {
+    // open bus pages written by the current instruction
+    written: Vec<u64>,
+}
+
+impl MissingHooks {
+    fn check(&self, id: u32) {
+        if ABORT_PENDING.replace(false) {
+            hook::bail(id);
+        }
+    }
+
+    fn access(&mut self, id: u32, paddr: u64, len: usize, rw: MemAccess) {
+        self.check(id);
+
+        if matches!(rw, MemAccess::Write | MemAccess::RW)
+            && OPEN_BUS_PAGES.load(Ordering::Relaxed) != 0
+        {
+            let open = open_bus();
+            for (pa, _, _) in paging::page_chunks(paddr, len) {
+                let pa = pa & !(PAGE_SIZE - 1);
+
+                if open.contains(&pa) && !self.written.contains(&pa) {
+                    self.written.push(pa);
+                }
+            }
+        }
+    }
+
+    /// Discard the writes made by the last instruction
+    pub(crate) fn flush(&mut self) {
+        for gpa in self.written.drain(..) {
+            discard_writes(gpa, PAGE_SIZE as usize);
+        }
+    }
+}
+
+impl Hooks for MissingHooks {
+    fn before_execution(&mut self, id: u32, _ins: *mut c_void) {
+        self.check(id);
+    }
+
+    fn after_execution(&mut self, _id: u32, _ins: *mut c_void) {
+        self.flush();
+    }
+
+    fn repeat_iteration(&mut self, _id: u32, _ins: *mut c_void) {
+        self.flush();
+    }
+
+    fn lin_access(
+        &mut self,
+        id: u32,
+        _vaddr: Address,
+        paddr: Address,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        self.access(id, paddr, len, rw);
+    }
+
+    fn phy_access(
+        &mut self,
+        id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        self.access(id, paddr, len, rw);
+    }
+}
+
+/// Install a physical page fault handler with a context
+///
+/// Same as `bochscpu_mem_missing_page()`, except `handler` is called with
+/// `ctx` and returns one of the `bochscpu_mem_missing_t` codes.
+///
+/// - `BOCHSCPU_MEM_MISSING_RESOLVED`: the handler inserted a page for the
+///   GPA. If it did not, the access panics as with no handler installed.
+/// - `BOCHSCPU_MEM_MISSING_UNRESOLVED`: the GPA is treated as open bus. The
+///   library backs it with a page reading as all ones and discards writes to
+///   it, guest writes once the writing instruction retires. The page is not
+///   counted by `bochscpu_mem_page_count()`, enumerated or snapshotted, and
+///   stays until it is removed or replaced.
+/// - `BOCHSCPU_MEM_MISSING_ABORT`: during `bochscpu_cpu_run()`, the cpu
+///   making the access abandons the current instruction and the run returns
+///   `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_PAGE_NOT_PRESENT`. Other
+///   cpus are not affected, and no page is left inserted for the GPA.
+///   Outside of a run, the library function making the access fails the same
+///   way instead.
+///
+/// Any other return value is handled as `BOCHSCPU_MEM_MISSING_ABORT`, with
+/// `BOCHSCPU_ERROR_INVALID_ARGUMENT` recorded instead.
+///
+/// # Note
+///
+/// This replaces any existing handler.
+///
+/// # Safety
+///
+/// `handler` is called with `ctx` from whichever thread makes the access.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_missing_page_ctx(
+    handler: extern "C" fn(ctx: *mut c_void, gpa: u64) -> u32,
+    ctx: *mut c_void,
+) -> bochscpu_status_t {
+    guard(|| unsafe { install(Some(Handler::Ctx(handler, ctx as usize))) })
+}
+
+/// Uninstall the physical page fault handler
+///
+/// Accesses to GPAs which are not present behave as if no handler had ever
+/// been installed.
+///
+/// # Safety
+///
+/// Must not be called while another thread is inside `bochscpu_cpu_run()`.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_mem_missing_page(handler: extern "C" fn(gpa: u64)) {
-    unsafe { missing_page(move |gpa| handler(gpa)) }
+pub unsafe extern "C" fn bochscpu_mem_missing_page_uninstall() -> bochscpu_status_t {
+    guard(|| unsafe { install(None) })
 }
 
 /// Translate GPA to HVA
 ///
-/// # Panics
+/// If the GPA does not exit, it will call the missing page handler.
 ///
-/// If the GPA does not exit, it will call the missing page handler. If no
-/// missing page handler is set or the missing page handler does not add the
-/// appropriate page, this will panic.
+/// # Returns
+///
+/// The HVA on success. If no missing page handler is set or the missing page
+/// handler does not add the appropriate page, NULL is returned.
 ///
-/// # Example
+/// Writes made through the HVA are not seen by dirty tracking. Instead,
+/// `bochscpu_snapshot_restore()` copies the page back on every restore until
+/// the GPA is remapped or removed.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_mem_phy_translate(gpa: u64) -> *mut u8 {
-    unsafe { phy_translate(gpa) }
+    guard_or(ptr::null_mut(), || unsafe {
+        if let Err((kind, msg)) = fault_in(gpa, 1) {
+            set_last_error(kind, &msg);
+            return ptr::null_mut();
+        }
+
+        dirty::escape(gpa);
+        phy_translate(gpa)
+    })
+}
+
+/// Check if a GPA is backed by an inserted page
+///
+/// This never calls the missing page handler.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_mem_page_present(gpa: u64) -> bool {
+    guard_or(false, || {
+        mapped_pages().contains_key(&(gpa & !(PAGE_SIZE - 1)))
+    })
+}
+
+/// Translate every page in `[gpa, gpa + len)` to HVA chunks without faulting
+fn phy_chunks(gpa: u64, len: usize) -> Result<Vec<paging::Chunk>, bochscpu_status_t> {
+    let pages = mapped_pages();
+
+    paging::translate_range(gpa, len, |pa| match pages.get(&(pa & !(PAGE_SIZE - 1))) {
+        Some(&hva) => Ok(hva as u64 + (pa & (PAGE_SIZE - 1))),
+        None => Err(()),
+    })
+    .map_err(|(pa, ())| {
+        fail(
+            bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT,
+            &format!("gpa {:#x} is not present", pa),
+        )
+    })
+}
+
+/// Translate GPA to HVA without faulting
+///
+/// Same as `bochscpu_mem_phy_translate()`, except the missing page handler is
+/// never called.
+///
+/// # Returns
+///
+/// The HVA on success, or NULL with `BOCHSCPU_ERROR_PAGE_NOT_PRESENT` if the
+/// GPA is not backed by an inserted page.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_mem_phy_translate_nofault(gpa: u64) -> *mut u8 {
+    guard_or(ptr::null_mut(), || match phy_chunks(gpa, 1) {
+        Ok(c) => {
+            dirty::escape(gpa);
+            c[0].0 as *mut u8
+        }
+        Err(_) => ptr::null_mut(),
+    })
+}
+
+/// Read from GPA without faulting
+///
+/// Same as `bochscpu_mem_phy_read()`, except the missing page handler is
+/// never called.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_PAGE_NOT_PRESENT` if any page
+/// is not present, in which case nothing is read.
+///
+/// # Safety
+///
+/// `hva` must be valid for writes of `sz` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_phy_read_nofault(
+    gpa: u64,
+    hva: *mut u8,
+    sz: usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let chunks = match phy_chunks(gpa, sz) {
+            Ok(c) => c,
+            Err(e) => return e,
+        };
+
+        for (src, off, len) in chunks {
+            ptr::copy_nonoverlapping(src as *const u8, hva.add(off), len);
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Write to GPA without faulting
+///
+/// Same as `bochscpu_mem_phy_write()`, except the missing page handler is
+/// never called.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` with `BOCHSCPU_ERROR_PAGE_NOT_PRESENT` if any page
+/// is not present, in which case nothing is written.
+///
+/// # Safety
+///
+/// `hva` must be valid for reads of `sz` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_phy_write_nofault(
+    gpa: u64,
+    hva: *const u8,
+    sz: usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let chunks = match phy_chunks(gpa, sz) {
+            Ok(c) => c,
+            Err(e) => return e,
+        };
+
+        for (dst, off, len) in chunks {
+            ptr::copy_nonoverlapping(hva.add(off), dst as *mut u8, len);
+        }
+        wrote(gpa, sz);
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
 }
 
 /// Translate GVA to GPA
@@ -61,43 +869,72 @@ pub unsafe extern "C" fn bochscpu_mem_phy_translate(gpa: u64) -> *mut u8 {
 ///
 /// # Returns
 ///
-/// Translated gpa on success, -1 on failure
+/// Translated gpa on success, -1 on failure. The reason for a failure can be
+/// retrieved with `bochscpu_last_error()`.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_mem_virt_translate(cr3: u64, gva: u64) -> u64 {
-    match virt_translate_checked(cr3, gva) {
-        Ok(a) => a,
-        Err(_) => 0xffff_ffff_ffff_ffff,
-    }
+    guard_or(0xffff_ffff_ffff_ffff, || {
+        match paging::translate(cr3, gva) {
+            Ok(a) => a,
+            Err(e) => {
+                virt_error(e, cr3, gva);
+                0xffff_ffff_ffff_ffff
+            }
+        }
+    })
 }
 
 /// Read from GPA
 ///
-/// # Panics
+/// If the GPA does not exist, it will call the missing page function.
 ///
-/// If the GPA does not exist, it will call the missing page function. If
-/// that function does not exist or does not resolve the fault, this routine
-/// will panic
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_PANIC` if the missing page function does not exist or
+/// does not resolve the fault, `BOCHSCPU_STATUS_ERROR` if it aborted.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_mem_phy_read(gpa: u64, hva: *mut u8, sz: usize) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_mem_phy_read(
+    gpa: u64,
+    hva: *mut u8,
+    sz: usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        if let Some(e) = fault_in_status(gpa, sz) {
+            return e;
+        }
+
         let s = slice::from_raw_parts_mut(hva, sz);
         phy_read_slice(gpa, s);
-    }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
 }
 
 /// Write to GPA
 ///
-/// # Panics
+/// If the GPA does not exist, it will call the missing page function.
+///
+/// # Returns
 ///
-/// If the GPA does not exist, it will call the missing page function. If
-/// that function does not exist or does not resolve the fault, this routine
-/// will panic
+/// `BOCHSCPU_STATUS_PANIC` if the missing page function does not exist or
+/// does not resolve the fault, `BOCHSCPU_STATUS_ERROR` if it aborted.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn bochscpu_mem_phy_write(gpa: u64, hva: *const u8, sz: usize) {
-    unsafe {
+pub unsafe extern "C" fn bochscpu_mem_phy_write(
+    gpa: u64,
+    hva: *const u8,
+    sz: usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        if let Some(e) = fault_in_status(gpa, sz) {
+            return e;
+        }
+
         let s = slice::from_raw_parts(hva, sz);
         phy_write(gpa, s);
-    }
+        wrote(gpa, sz);
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
 }
 
 /// Write to GVA
@@ -106,7 +943,8 @@ pub unsafe extern "C" fn bochscpu_mem_phy_write(gpa: u64, hva: *const u8, sz: us
 ///
 /// # Returns
 ///
-/// Zero on success, non-zero on failure
+/// Zero on success, non-zero on failure. The reason for a failure can be
+/// retrieved with `bochscpu_last_error()`.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_mem_virt_write(
     cr3: u64,
@@ -114,14 +952,17 @@ pub unsafe extern "C" fn bochscpu_mem_virt_write(
     hva: *const u8,
     sz: usize,
 ) -> i32 {
-    unsafe {
+    guard_or(-1, || unsafe {
         let s = slice::from_raw_parts(hva, sz);
 
-        match virt_write_checked(cr3, gva, s) {
+        match paging::virt_write(cr3, gva, s) {
             Ok(_) => 0,
-            Err(_) => -1,
+            Err((va, e)) => {
+                virt_error(e, cr3, va);
+                -1
+            }
         }
-    }
+    })
 }
 
 /// Read from GVA
@@ -130,7 +971,8 @@ pub unsafe extern "C" fn bochscpu_mem_virt_write(
 ///
 /// # Returns
 ///
-/// Zero on success, non-zero on failure
+/// Zero on success, non-zero on failure. The reason for a failure can be
+/// retrieved with `bochscpu_last_error()`.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn bochscpu_mem_virt_read(
     cr3: u64,
@@ -138,12 +980,446 @@ pub unsafe extern "C" fn bochscpu_mem_virt_read(
     hva: *mut u8,
     sz: usize,
 ) -> i32 {
-    unsafe {
+    guard_or(-1, || unsafe {
         let s = slice::from_raw_parts_mut(hva, sz);
 
-        match virt_read_slice_checked(cr3, gva, s) {
+        match paging::virt_read(cr3, gva, s) {
             Ok(_) => 0,
-            Err(_) => -1,
+            Err((va, e)) => {
+                virt_error(e, cr3, va);
+                -1
+            }
+        }
+    })
+}
+
+/// Write to GVA, stopping at the first failure
+///
+/// Same as `bochscpu_mem_virt_write()`, except pages are written in order
+/// until one fails to translate, rather than checking every page up front.
+/// If the write stops early the GVA which failed to translate is written to
+/// `fault_gva` if it is not NULL, and the reason can be retrieved with
+/// `bochscpu_last_error()`.
+///
+/// # Returns
+///
+/// The number of bytes written, which is `sz` on success.
+///
+/// # Safety
+///
+/// `hva` must be valid for reads of `sz` bytes, and `fault_gva` NULL or valid for
+/// writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_write_partial(
+    cr3: u64,
+    gva: u64,
+    hva: *const u8,
+    sz: usize,
+    fault_gva: *mut u64,
+) -> usize {
+    guard_or(0, || unsafe {
+        let s = slice::from_raw_parts(hva, sz);
+
+        let (n, err) = paging::virt_write_partial(cr3, gva, s);
+        if let Some((va, e)) = err {
+            virt_error(e, cr3, va);
+            if !fault_gva.is_null() {
+                *fault_gva = va;
+            }
+        }
+
+        n
+    })
+}
+
+/// Read from GVA, stopping at the first failure
+///
+/// Same as `bochscpu_mem_virt_read()`, except pages are read in order until
+/// one fails to translate, rather than checking every page up front. If the
+/// read stops early the GVA which failed to translate is written to
+/// `fault_gva` if it is not NULL, and the reason can be retrieved with
+/// `bochscpu_last_error()`.
+///
+/// # Returns
+///
+/// The number of bytes read, which is `sz` on success.
+///
+/// # Safety
+///
+/// `hva` must be valid for writes of `sz` bytes, and `fault_gva` NULL or valid
+/// for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_partial(
+    cr3: u64,
+    gva: u64,
+    hva: *mut u8,
+    sz: usize,
+    fault_gva: *mut u64,
+) -> usize {
+    guard_or(0, || unsafe {
+        let s = slice::from_raw_parts_mut(hva, sz);
+
+        let (n, err) = paging::virt_read_partial(cr3, gva, s);
+        if let Some((va, e)) = err {
+            virt_error(e, cr3, va);
+            if !fault_gva.is_null() {
+                *fault_gva = va;
+            }
+        }
+
+        n
+    })
+}
+
+unsafe fn virt_walk(
+    cr3: u64,
+    gva: u64,
+    la57: bool,
+    walk: *mut bochscpu_mem_walk_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let w = paging::walk(cr3, gva, la57);
+        *walk = w;
+
+        match w.result() {
+            Ok(_) => bochscpu_status_t::BOCHSCPU_STATUS_OK,
+            Err(e) => {
+                virt_error(e, cr3, gva);
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            }
+        }
+    })
+}
+
+/// Walk the 4-level page tables for a GVA
+///
+/// Use the provided cr3 to walk the page tables, recording every paging
+/// structure entry read, the page size and the effective permissions in
+/// `walk`. `walk` is filled in even if the walk fails, in which case
+/// `walk->fault_level` is the level it stopped at.
+///
+/// Paging structures are read through the missing page handler. One in a
+/// page which is still not present afterwards stops the walk with
+/// `BOCHSCPU_ERROR_PAGE_NOT_PRESENT`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the GVA could not be translated.
+///
+/// # Safety
+///
+/// `walk` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_walk(
+    cr3: u64,
+    gva: u64,
+    walk: *mut bochscpu_mem_walk_t,
+) -> bochscpu_status_t {
+    unsafe { virt_walk(cr3, gva, false, walk) }
+}
+
+/// Walk the 5-level page tables for a GVA
+///
+/// Same as `bochscpu_mem_virt_walk()`, for use when CR4.LA57 is set.
+///
+/// # Safety
+///
+/// `walk` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_walk_la57(
+    cr3: u64,
+    gva: u64,
+    walk: *mut bochscpu_mem_walk_t,
+) -> bochscpu_status_t {
+    unsafe { virt_walk(cr3, gva, true, walk) }
+}
+
+/// Enumerate the 4-level address space rooted at cr3
+///
+/// Calls `cb` with `ctx` for each region of contiguous GVAs mapped to
+/// contiguous GPAs with the same page size and permissions, in ascending GVA
+/// order with the lower half first. The enumeration stops early if `cb`
+/// returns false. Entries which would fault, for example because a reserved
+/// bit is set, are skipped.
+///
+/// Paging structures are read through the missing page handler. A paging
+/// structure in a page which is still not present afterwards is skipped along
+/// with everything it maps.
+///
+/// # Safety
+///
+/// `cb` must be safe to call with `ctx`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_enumerate(
+    cr3: u64,
+    cb: extern "C" fn(ctx: *mut c_void, region: *const bochscpu_mem_region_t) -> bool,
+    ctx: *mut c_void,
+) -> bochscpu_status_t {
+    guard(|| unsafe { paging::enumerate(cr3, false, |r| cb(ctx, r)) })
+}
+
+/// Enumerate the 5-level address space rooted at cr3
+///
+/// Same as `bochscpu_mem_virt_enumerate()`, for use when CR4.LA57 is set.
+///
+/// # Safety
+///
+/// `cb` must be safe to call with `ctx`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_enumerate_la57(
+    cr3: u64,
+    cb: extern "C" fn(ctx: *mut c_void, region: *const bochscpu_mem_region_t) -> bool,
+    ctx: *mut c_void,
+) -> bochscpu_status_t {
+    guard(|| unsafe { paging::enumerate(cr3, true, |r| cb(ctx, r)) })
+}
+
+/// Translate GVA to GPA, checking page permissions
+///
+/// Use the provided cr3 to translate the GVA, and check the access described
+/// by `access` is allowed the same way the cpu would.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the cpu would raise an exception, in which case
+/// the exception is written to `fault` if it is not NULL. A non-canonical GVA
+/// raises #GP(0), every other failure raises #PF with `fault->address` being
+/// the value cr2 would take. Permission failures are reported as
+/// `BOCHSCPU_ERROR_ACCESS_VIOLATION`.
+///
+/// Paging structures are read through the missing page handler. One in a
+/// page which is still not present afterwards fails with
+/// `BOCHSCPU_ERROR_PAGE_NOT_PRESENT` and leaves `fault` untouched, as no
+/// exception can be determined.
+///
+/// # Safety
+///
+/// `access` must be valid for reads, `gpa` valid for writes, and `fault` NULL or
+/// valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_translate_access(
+    cr3: u64,
+    gva: u64,
+    access: *const bochscpu_mem_access_t,
+    gpa: *mut u64,
+    fault: *mut bochscpu_mem_fault_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let a = &*access;
+
+        let kinds = [
+            BOCHSCPU_HOOK_MEM_READ,
+            BOCHSCPU_HOOK_MEM_WRITE,
+            BOCHSCPU_HOOK_MEM_EXECUTE,
+            BOCHSCPU_HOOK_MEM_RW,
+        ];
+        if let Some(e) = bad_access(a.access, &kinds) {
+            return e;
+        }
+
+        match paging::translate_access(cr3, gva, a) {
+            Ok(pa) => {
+                *gpa = pa;
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err(f) => access_error(f, cr3, gva, fault),
+        }
+    })
+}
+
+/// Read from GVA, checking page permissions
+///
+/// Same as `bochscpu_mem_virt_read()`, except every page is checked as by
+/// `bochscpu_mem_virt_translate_access()`. `access->access` must be
+/// `BOCHSCPU_HOOK_MEM_READ` or `BOCHSCPU_HOOK_MEM_EXECUTE`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if any page would fault, in which case nothing is
+/// read and the first exception is written to `fault` if it is not NULL.
+///
+/// # Safety
+///
+/// `hva` must be valid for writes of `sz` bytes, `access` valid for reads, and
+/// `fault` NULL or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_access(
+    cr3: u64,
+    gva: u64,
+    hva: *mut u8,
+    sz: usize,
+    access: *const bochscpu_mem_access_t,
+    fault: *mut bochscpu_mem_fault_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let a = &*access;
+
+        let kinds = [BOCHSCPU_HOOK_MEM_READ, BOCHSCPU_HOOK_MEM_EXECUTE];
+        if let Some(e) = bad_access(a.access, &kinds) {
+            return e;
+        }
+
+        let s = slice::from_raw_parts_mut(hva, sz);
+        match paging::translate_range(gva, sz, |va| paging::translate_access_data(cr3, va, a)) {
+            Ok(chunks) => {
+                paging::read_chunks(&chunks, s);
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err((va, f)) => access_error(f, cr3, va, fault),
+        }
+    })
+}
+
+/// Write to GVA, checking page permissions
+///
+/// Same as `bochscpu_mem_virt_write()`, except every page is checked as by
+/// `bochscpu_mem_virt_translate_access()`. `access->access` must be
+/// `BOCHSCPU_HOOK_MEM_WRITE` or `BOCHSCPU_HOOK_MEM_RW`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if any page would fault, in which case nothing is
+/// written and the first exception is written to `fault` if it is not NULL.
+///
+/// # Safety
+///
+/// `hva` must be valid for reads of `sz` bytes, `access` valid for reads, and
+/// `fault` NULL or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_write_access(
+    cr3: u64,
+    gva: u64,
+    hva: *const u8,
+    sz: usize,
+    access: *const bochscpu_mem_access_t,
+    fault: *mut bochscpu_mem_fault_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let a = &*access;
+
+        let kinds = [BOCHSCPU_HOOK_MEM_WRITE, BOCHSCPU_HOOK_MEM_RW];
+        if let Some(e) = bad_access(a.access, &kinds) {
+            return e;
+        }
+
+        let s = slice::from_raw_parts(hva, sz);
+        match paging::translate_range(gva, sz, |va| paging::translate_access_data(cr3, va, a)) {
+            Ok(chunks) => {
+                paging::write_chunks(&chunks, s);
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err((va, f)) => access_error(f, cr3, va, fault),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::bochscpu_last_error;
+    use crate::testutil::{lock, page};
+
+    extern "C" fn handler(ctx: *mut c_void, gpa: u64) -> u32 {
+        unsafe { *(ctx as *mut u64) += 1 };
+
+        match gpa & !(PAGE_SIZE - 1) {
+            0xb0_0000 => {
+                page(0xb0_0000);
+                bochscpu_mem_missing_t::BOCHSCPU_MEM_MISSING_RESOLVED as u32
+            }
+            0xb0_1000 => bochscpu_mem_missing_t::BOCHSCPU_MEM_MISSING_UNRESOLVED as u32,
+            0xb0_2000 => bochscpu_mem_missing_t::BOCHSCPU_MEM_MISSING_ABORT as u32,
+            _ => 7,
+        }
+    }
+
+    extern "C" fn collect(ctx: *mut c_void, gpa: u64, _hva: *mut u8) -> bool {
+        unsafe { (*(ctx as *mut Vec<u64>)).push(gpa) };
+        true
+    }
+
+    fn page_gpas() -> Vec<u64> {
+        let mut gpas: Vec<u64> = Vec::new();
+        unsafe { bochscpu_mem_page_enumerate(collect, &mut gpas as *mut _ as _) };
+
+        gpas
+    }
+
+    fn read(gpa: u64) -> (bochscpu_status_t, u8) {
+        let mut v = 0;
+        let status = unsafe { bochscpu_mem_phy_read(gpa, &mut v, 1) };
+
+        (status, v)
+    }
+
+    #[test]
+    fn missing_page_codes() {
+        let _l = lock();
+        let mut calls = 0u64;
+
+        unsafe {
+            bochscpu_mem_missing_page_ctx(handler, &mut calls as *mut u64 as _);
+
+            assert_eq!(read(0xb0_0010), (bochscpu_status_t::BOCHSCPU_STATUS_OK, 0));
+            assert_eq!(
+                read(0xb0_1010),
+                (bochscpu_status_t::BOCHSCPU_STATUS_OK, 0xff)
+            );
+            assert_eq!(calls, 2);
+
+            // open bus is not reported as memory and ignores writes
+            assert!(bochscpu_mem_page_present(0xb0_1000));
+            assert!(!page_gpas().contains(&0xb0_1000));
+            let v = 0u8;
+            assert_eq!(
+                bochscpu_mem_phy_write(0xb0_1010, &v, 1),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(
+                read(0xb0_1010),
+                (bochscpu_status_t::BOCHSCPU_STATUS_OK, 0xff)
+            );
+
+            // no page is left behind, so the handler is asked again
+            for _ in 0..2 {
+                assert_eq!(read(0xb0_2000).0, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+                assert_eq!(
+                    bochscpu_last_error(),
+                    bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT
+                );
+                assert!(!bochscpu_mem_page_present(0xb0_2000));
+            }
+            assert_eq!(calls, 4);
+
+            assert_eq!(read(0xb0_3000).0, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+            assert_eq!(
+                bochscpu_last_error(),
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT
+            );
+            assert!(!bochscpu_mem_page_present(0xb0_3000));
+
+            bochscpu_mem_missing_page_uninstall();
+            bochscpu_mem_page_remove(0xb0_1000);
+        }
+    }
+
+    #[test]
+    fn unaligned_insert() {
+        let _l = lock();
+        let hva = page(0xb0_8000);
+
+        unsafe {
+            for (gpa, hva) in [(0xb0_9010, hva), (0xb0_9000, hva.add(0x10))] {
+                assert_eq!(
+                    bochscpu_mem_page_insert(gpa, hva),
+                    bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+                );
+                assert_eq!(
+                    bochscpu_last_error(),
+                    bochscpu_error_t::BOCHSCPU_ERROR_UNALIGNED
+                );
+                assert!(!bochscpu_mem_page_present(0xb0_9000));
+            }
         }
     }
 }
@@ -0,0 +1,429 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use bochscpu::cpu::Cpu;
+use bochscpu::hook::*;
+use bochscpu::mem::{phy_read_slice, phy_write};
+use bochscpu::{Address, PhyAddress};
+
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or};
+use crate::hook;
+use crate::mem;
+use crate::paging::PAGE_SIZE;
+
+/// MMIO load callback
+///
+/// Called with the GPA and size in bytes, at most 8, of a guest load. Returns
+/// the little endian value the loaded bytes should hold for subsequent loads.
+#[allow(non_camel_case_types)]
+pub type bochscpu_mmio_read_t =
+    Option<extern "C" fn(ctx: *mut c_void, gpa: u64, len: usize) -> u64>;
+
+/// MMIO store callback
+///
+/// Called with the GPA, size in bytes, at most 8, and little endian value of
+/// a guest store.
+#[allow(non_camel_case_types)]
+pub type bochscpu_mmio_write_t =
+    Option<extern "C" fn(ctx: *mut c_void, gpa: u64, len: usize, val: u64)>;
+
+#[derive(Clone, Copy)]
+struct Region {
+    gpa: u64,
+    len: u64,
+    ctx: *mut c_void,
+    read: bochscpu_mmio_read_t,
+    write: bochscpu_mmio_write_t,
+}
+
+impl Region {
+    fn overlaps(&self, gpa: u64, len: u64) -> bool {
+        gpa < self.gpa + self.len && self.gpa < gpa.saturating_add(len)
+    }
+
+    /// Split an access into pieces of at most 8 bytes, clamped to the region
+    fn pieces(&self, gpa: u64, len: usize) -> impl Iterator<Item = (u64, usize)> {
+        let start = gpa.max(self.gpa);
+        let end = gpa.saturating_add(len as u64).min(self.gpa + self.len);
+
+        (start..end)
+            .step_by(8)
+            .map(move |pa| (pa, (end - pa).min(8) as usize))
+    }
+
+    unsafe fn load(&self, gpa: u64, len: usize) {
+        let Some(read) = self.read else {
+            return;
+        };
+
+        for (pa, sz) in self.pieces(gpa, len) {
+            let val = read(self.ctx, pa, sz);
+            unsafe { phy_write(pa, &val.to_le_bytes()[..sz]) };
+        }
+    }
+
+    unsafe fn store(&self, gpa: u64, len: usize) {
+        let Some(write) = self.write else {
+            return;
+        };
+
+        for (pa, sz) in self.pieces(gpa, len) {
+            let mut buf = [0u8; 8];
+            unsafe { phy_read_slice(pa, &mut buf[..sz]) };
+            write(self.ctx, pa, sz, u64::from_le_bytes(buf));
+        }
+    }
+}
+
+// ctx is only ever handed back to the callbacks
+unsafe impl Send for Region {}
+
+static REGIONS: Mutex<Vec<Region>> = Mutex::new(Vec::new());
+
+fn regions() -> MutexGuard<'static, Vec<Region>> {
+    REGIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// bumped whenever REGIONS changes, so running cpus only take the lock to
+// pick up a new set of regions
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn enabled() -> bool {
+    !regions().is_empty()
+}
+
+/// Forwards guest accesses to MMIO regions to their callbacks
+#[derive(Default)]
+pub(crate) struct MmioHooks {
+    // copy of REGIONS as of `generation`
+    regions: Vec<Region>,
+    generation: u64,
+    // rip of the executing instruction
+    rip: u64,
+    // loads already served by read_cb, for when the instruction is restarted
+    primed: Vec<(u64, usize)>,
+    // stores are reported before they land, so deliver them once the
+    // instruction has retired
+    stores: Vec<(Region, u64, usize)>,
+}
+
+impl MmioHooks {
+    /// Deliver pending stores
+    pub(crate) fn flush(&mut self) {
+        for (r, gpa, len) in self.stores.drain(..) {
+            unsafe { r.store(gpa, len) };
+        }
+    }
+
+    fn refresh(&mut self) {
+        let generation = GENERATION.load(Ordering::Acquire);
+
+        if generation != self.generation {
+            self.regions = regions().clone();
+            self.generation = generation;
+        }
+    }
+
+    fn access(&mut self, id: u32, gpa: u64, len: usize, rw: MemAccess) {
+        self.refresh();
+
+        if !self.regions.iter().any(|r| r.overlaps(gpa, len as u64)) {
+            return;
+        }
+
+        let served = match rw {
+            MemAccess::Read | MemAccess::RW => self.load(id, gpa, len),
+            MemAccess::Write => true,
+            MemAccess::Execute => false,
+        };
+
+        if served && matches!(rw, MemAccess::Write | MemAccess::RW) {
+            let hits = self.regions.iter().filter(|r| r.overlaps(gpa, len as u64));
+            self.stores.extend(hits.map(|r| (*r, gpa, len)));
+        }
+    }
+
+    /// Serve a load from read_cb
+    ///
+    /// Loads are reported once they have completed, so the backing pages are
+    /// updated and the instruction is run again to load the new value.
+    /// Returns whether the load already holds the value from read_cb.
+    fn load(&mut self, id: u32, gpa: u64, len: usize) -> bool {
+        // kept until the instruction retires, every restart loads them again
+        if self.primed.contains(&(gpa, len)) {
+            return true;
+        }
+
+        for r in self.regions.iter().filter(|r| r.overlaps(gpa, len as u64)) {
+            unsafe { r.load(gpa, len) };
+        }
+
+        // the instruction is executed again, along with its stores
+        self.stores.clear();
+        self.primed.push((gpa, len));
+        hook::restart(id, self.rip);
+
+        false
+    }
+}
+
+impl Hooks for MmioHooks {
+    fn before_execution(&mut self, id: u32, _ins: *mut c_void) {
+        let rip = unsafe { Cpu::from(id).rip() };
+
+        // the instruction was abandoned rather than restarted
+        if rip != self.rip {
+            self.primed.clear();
+        }
+        self.rip = rip;
+    }
+
+    fn after_execution(&mut self, _id: u32, _ins: *mut c_void) {
+        self.primed.clear();
+        self.flush();
+    }
+
+    fn repeat_iteration(&mut self, _id: u32, _ins: *mut c_void) {
+        self.primed.clear();
+        self.flush();
+    }
+
+    fn lin_access(
+        &mut self,
+        id: u32,
+        _vaddr: Address,
+        paddr: Address,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        self.access(id, paddr, len, rw);
+    }
+
+    fn phy_access(
+        &mut self,
+        id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        self.access(id, paddr, len, rw);
+    }
+}
+
+/// Register an MMIO region
+///
+/// Guest loads and stores to the `len` bytes at `gpa` are reported to
+/// `read_cb` and `write_cb` with `ctx`, split into pieces of at most 8
+/// bytes. Either callback may be NULL. `gpa` and `len` must be page aligned.
+///
+/// The region is backed by zeroed pages owned by the library, replacing any
+/// pages already mapped there. These hold the current register values:
+///
+/// - Stores update the backing pages, then `write_cb` is called with the
+///   stored value once the instruction completes.
+/// - Loads call `read_cb`, and the value it returns is written to the
+///   backing pages before the instruction loads it. The core only reports
+///   a load once it has completed, so the instruction is executed again,
+///   once per load from the region, with stores it made to the region
+///   before the load reported only once. `read_cb` is called once per load.
+///
+/// Every other hook sees each execution of such an instruction: hooks passed
+/// to `bochscpu_cpu_run()` get `before_execution` and `lin_access` again,
+/// breakpoints count another hit, watchpoints trigger again and dirty
+/// tracking records the stores again.
+///
+/// Accesses reported as physical, such as page walks through the region,
+/// are forwarded the same way. Instruction fetches from the region, and
+/// accesses made through the `bochscpu_mem_*` functions, only touch the
+/// backing pages.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the range is not page aligned, empty, or
+/// overlaps an existing region.
+///
+/// # Safety
+///
+/// `read_cb` and `write_cb` are called with `ctx` from whichever thread
+/// runs a cpu, until the region is unregistered.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_mmio_register(
+    gpa: u64,
+    len: u64,
+    ctx: *mut c_void,
+    read_cb: bochscpu_mmio_read_t,
+    write_cb: bochscpu_mmio_write_t,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        use bochscpu_error_t::*;
+
+        if (gpa | len) & (PAGE_SIZE - 1) != 0 {
+            return fail(
+                BOCHSCPU_ERROR_UNALIGNED,
+                &format!("mmio gpa {:#x} length {:#x} is not page aligned", gpa, len),
+            );
+        }
+
+        let Some(end) = gpa.checked_add(len).filter(|_| len != 0) else {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("mmio gpa {:#x} length {:#x} is not a valid range", gpa, len),
+            );
+        };
+
+        let mut regions = regions();
+
+        if regions.iter().any(|r| r.overlaps(gpa, len)) {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("mmio gpa {:#x} overlaps an existing region", gpa),
+            );
+        }
+
+        for pa in (gpa..end).step_by(PAGE_SIZE as usize) {
+            mem::insert_owned(pa, 0);
+        }
+
+        regions.push(Region {
+            gpa,
+            len,
+            ctx,
+            read: read_cb,
+            write: write_cb,
+        });
+        GENERATION.fetch_add(1, Ordering::Release);
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Unregister an MMIO region
+///
+/// `gpa` must be the start of a region added with
+/// `bochscpu_mem_mmio_register()`. Its backing pages are removed.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if no region starts at `gpa`.
+///
+/// # Safety
+///
+/// Must not be called while another thread is inside `bochscpu_cpu_run()`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_mmio_unregister(gpa: u64) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let region = {
+            let mut regions = regions();
+            let idx = regions.iter().position(|r| r.gpa == gpa);
+            let region = idx.map(|idx| regions.remove(idx));
+            GENERATION.fetch_add(1, Ordering::Release);
+            region
+        };
+
+        let Some(r) = region else {
+            return fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("no mmio region starts at gpa {:#x}", gpa),
+            );
+        };
+
+        for pa in (r.gpa..r.gpa + r.len).step_by(PAGE_SIZE as usize) {
+            mem::remove(pa);
+        }
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+    use crate::testutil::lock;
+
+    const BASE: u64 = 0xc0_0000;
+
+    extern "C" fn read_cb(ctx: *mut c_void, gpa: u64, len: usize) -> u64 {
+        unsafe { (*(ctx as *mut Vec<(u64, usize, u64)>)).push((gpa, len, 0)) };
+        gpa
+    }
+
+    extern "C" fn write_cb(ctx: *mut c_void, gpa: u64, len: usize, val: u64) {
+        unsafe { (*(ctx as *mut Vec<(u64, usize, u64)>)).push((gpa, len, val)) };
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let _l = lock();
+        let mut log: Vec<(u64, usize, u64)> = Vec::new();
+        let id = 0x303;
+
+        unsafe {
+            let ctx = &mut log as *mut Vec<_> as *mut c_void;
+            let status =
+                bochscpu_mem_mmio_register(BASE, 0x1000, ctx, Some(read_cb), Some(write_cb));
+            assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+
+            Cpu::from(id).set_rip(0x1234);
+            let mut hooks = MmioHooks::default();
+            hooks.before_execution(id, ptr::null_mut());
+
+            // a load straddling the start of the region only reports the
+            // bytes inside it, then restarts the instruction
+            hooks.phy_access(id, BASE - 4, 8, MemType::Uc, MemAccess::Read);
+            assert_eq!(log, [(BASE, 4, 0)]);
+            assert_eq!(hook::take_restart(), Some(0x1234));
+
+            let mut val = [0u8; 4];
+            phy_read_slice(BASE, &mut val);
+            assert_eq!(u32::from_le_bytes(val), BASE as u32);
+
+            // the load is served when the instruction runs again
+            hooks.before_execution(id, ptr::null_mut());
+            hooks.phy_access(id, BASE - 4, 8, MemType::Uc, MemAccess::Read);
+            assert_eq!(log.len(), 1);
+            assert_eq!(hook::take_restart(), None);
+
+            // stores are delivered once the instruction retires
+            phy_write(BASE + 8, &0xaabbu16.to_le_bytes());
+            hooks.lin_access(id, 0, BASE + 8, 2, MemType::Uc, MemAccess::Write);
+            assert_eq!(log.len(), 1);
+            hooks.after_execution(id, ptr::null_mut());
+            assert_eq!(log[1], (BASE + 8, 2, 0xaabb));
+
+            // primed loads do not outlive the instruction
+            hooks.lin_access(id, 0, BASE - 4, 8, MemType::Uc, MemAccess::Read);
+            assert_eq!(log.len(), 3);
+            assert_eq!(hook::take_restart(), Some(0x1234));
+            hooks.after_execution(id, ptr::null_mut());
+
+            // an instruction loading twice is restarted once per load, and
+            // each load only reaches read_cb once
+            log.clear();
+            for _ in 0..3 {
+                hooks.before_execution(id, ptr::null_mut());
+                hooks.phy_access(id, BASE + 0x10, 8, MemType::Uc, MemAccess::Read);
+                if hook::take_restart().is_some() {
+                    continue;
+                }
+
+                hooks.phy_access(id, BASE + 0x20, 4, MemType::Uc, MemAccess::Read);
+                if hook::take_restart().is_some() {
+                    continue;
+                }
+
+                hooks.after_execution(id, ptr::null_mut());
+            }
+            assert_eq!(log, [(BASE + 0x10, 8, 0), (BASE + 0x20, 4, 0)]);
+
+            assert_eq!(
+                bochscpu_mem_mmio_unregister(BASE),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+        }
+    }
+}
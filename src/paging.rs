@@ -0,0 +1,808 @@
+use std::ops::ControlFlow;
+use std::panic;
+
+use bochscpu::mem::*;
+
+use crate::error::bochscpu_error_t;
+use crate::hook::bochscpu_hook_mem_t::{self, *};
+use crate::mem;
+
+pub(crate) const PAGE_SIZE: u64 = 0x1000;
+
+/// A piece of a virtual range which does not cross a page boundary
+///
+/// `(address, offset into the range, length)`
+pub(crate) type Chunk = (u64, usize, usize);
+
+pub(crate) const PTE_PRESENT: u64 = 1 << 0;
+pub(crate) const PTE_WRITE: u64 = 1 << 1;
+pub(crate) const PTE_USER: u64 = 1 << 2;
+pub(crate) const PTE_LARGE: u64 = 1 << 7;
+pub(crate) const PTE_NX: u64 = 1 << 63;
+pub(crate) const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// bits 29:13 of a 1G PDPTE and 20:13 of a 2M PDE must be zero
+const PDPTE_1G_RSVD: u64 = 0x3fff_e000;
+const PDE_2M_RSVD: u64 = 0x001f_e000;
+
+/// Paging structure level
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[repr(C)]
+pub enum bochscpu_mem_level_t {
+    BOCHSCPU_MEM_LEVEL_PML5 = 0,
+    BOCHSCPU_MEM_LEVEL_PML4 = 1,
+    BOCHSCPU_MEM_LEVEL_PDPT = 2,
+    BOCHSCPU_MEM_LEVEL_PD = 3,
+    BOCHSCPU_MEM_LEVEL_PT = 4,
+    /// No level, used when a walk did not fail
+    BOCHSCPU_MEM_LEVEL_NONE = 5,
+}
+
+pub(crate) const LEVELS: [bochscpu_mem_level_t; 5] = [
+    bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PML5,
+    bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PML4,
+    bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PDPT,
+    bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PD,
+    bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PT,
+];
+
+impl bochscpu_mem_level_t {
+    /// Number of GVA bits translated by the levels below this one
+    pub(crate) fn shift(self) -> u32 {
+        48 - 9 * self as u32
+    }
+
+    fn not_present(self) -> bochscpu_error_t {
+        use bochscpu_error_t::*;
+        use bochscpu_mem_level_t::*;
+
+        match self {
+            BOCHSCPU_MEM_LEVEL_PML5 => BOCHSCPU_ERROR_PML5E_NOT_PRESENT,
+            BOCHSCPU_MEM_LEVEL_PML4 => BOCHSCPU_ERROR_PML4E_NOT_PRESENT,
+            BOCHSCPU_MEM_LEVEL_PDPT => BOCHSCPU_ERROR_PDPTE_NOT_PRESENT,
+            BOCHSCPU_MEM_LEVEL_PD => BOCHSCPU_ERROR_PDE_NOT_PRESENT,
+            BOCHSCPU_MEM_LEVEL_PT | BOCHSCPU_MEM_LEVEL_NONE => BOCHSCPU_ERROR_PTE_NOT_PRESENT,
+        }
+    }
+}
+
+/// Result of a page table walk
+///
+/// The `entry_gpa` and `entry` arrays are indexed by `bochscpu_mem_level_t`,
+/// and levels which were not walked are zero. The effective permissions
+/// accumulate over every level walked, even if the walk failed.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct bochscpu_mem_walk_t {
+    /// GPA of the paging structure entry read at each level
+    pub entry_gpa: [u64; 5],
+    /// Raw value of the paging structure entry read at each level
+    pub entry: [u64; 5],
+    /// Translated GPA, zero if the walk failed
+    pub gpa: u64,
+    /// Size of the mapping, 4K, 2M or 1G. Zero if the walk failed
+    pub page_size: u64,
+    /// Effective U/S, the page is accessible from user mode
+    pub user: bool,
+    /// Effective R/W, the page is writable
+    pub writable: bool,
+    /// Effective XD, the page is not executable
+    pub nx: bool,
+    /// Level the walk stopped at, `BOCHSCPU_MEM_LEVEL_NONE` on success
+    pub fault_level: bochscpu_mem_level_t,
+    /// Reason the walk failed, `BOCHSCPU_ERROR_NONE` on success
+    pub error: bochscpu_error_t,
+}
+
+impl bochscpu_mem_walk_t {
+    pub(crate) fn result(&self) -> Result<u64, bochscpu_error_t> {
+        match self.error {
+            bochscpu_error_t::BOCHSCPU_ERROR_NONE => Ok(self.gpa),
+            e => Err(e),
+        }
+    }
+}
+
+/// Check if a GVA is canonical for 4-level paging
+pub(crate) fn is_canonical(gva: u64) -> bool {
+    let top = (gva as i64) >> 47;
+
+    top == 0 || top == -1
+}
+
+pub(crate) fn is_canonical_la57(gva: u64) -> bool {
+    let top = (gva as i64) >> 56;
+
+    top == 0 || top == -1
+}
+
+/// Split the range `[gva, gva + len)` into chunks which do not cross a page
+pub(crate) fn page_chunks(gva: u64, len: usize) -> impl Iterator<Item = Chunk> {
+    let mut off = 0;
+
+    std::iter::from_fn(move || {
+        if off >= len {
+            return None;
+        }
+
+        let cur = gva.wrapping_add(off as u64);
+        let left = (PAGE_SIZE - (cur & (PAGE_SIZE - 1))) as usize;
+        let sz = left.min(len - off);
+        let r = (cur, off, sz);
+
+        off += sz;
+
+        Some(r)
+    })
+}
+
+/// Read paging structures from a single page
+///
+/// The page is faulted in with the missing page handler, then read from the
+/// inserted pages rather than through the core, so a page the handler did not
+/// provide is reported as `BOCHSCPU_ERROR_PAGE_NOT_PRESENT`.
+unsafe fn read_table(gpa: u64, buf: &mut [u8]) -> Result<(), bochscpu_error_t> {
+    debug_assert!((gpa & (PAGE_SIZE - 1)) as usize + buf.len() <= PAGE_SIZE as usize);
+
+    mem::fault_in(gpa, buf.len()).map_err(|(kind, _)| kind)?;
+
+    let hva = mem::mapped_pages()
+        .get(&(gpa & !(PAGE_SIZE - 1)))
+        .copied()
+        .ok_or(bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT)?;
+
+    let src = (hva as u64 + (gpa & (PAGE_SIZE - 1))) as *const u8;
+    unsafe { std::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+
+    Ok(())
+}
+
+/// Read a paging structure entry
+pub(crate) unsafe fn read_entry(gpa: u64) -> Result<u64, bochscpu_error_t> {
+    let mut buf = [0u8; 8];
+
+    unsafe { read_table(gpa, &mut buf)? };
+
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Classify a present paging structure entry
+///
+/// Returns the page size if the entry maps a page, or None if it references a
+/// paging structure at the next level.
+fn classify(level: bochscpu_mem_level_t, entry: u64) -> Result<Option<u64>, bochscpu_error_t> {
+    use bochscpu_mem_level_t::*;
+
+    // bit 7 is PAT rather than PS in a PTE
+    let leaf = match level {
+        BOCHSCPU_MEM_LEVEL_PT => true,
+        _ => entry & PTE_LARGE != 0,
+    };
+
+    let rsvd = match level {
+        BOCHSCPU_MEM_LEVEL_PML5 | BOCHSCPU_MEM_LEVEL_PML4 => leaf,
+        BOCHSCPU_MEM_LEVEL_PDPT => leaf && entry & PDPTE_1G_RSVD != 0,
+        BOCHSCPU_MEM_LEVEL_PD => leaf && entry & PDE_2M_RSVD != 0,
+        _ => false,
+    };
+    if rsvd {
+        return Err(bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT);
+    }
+
+    Ok(leaf.then(|| 1 << level.shift()))
+}
+
+/// Walk the page tables rooted at cr3 for a GVA
+///
+/// Uses 5-level paging if `la57` is set, 4-level paging otherwise. Access
+/// permissions are not checked.
+pub(crate) unsafe fn walk(cr3: u64, gva: u64, la57: bool) -> bochscpu_mem_walk_t {
+    use bochscpu_error_t::*;
+    use bochscpu_mem_level_t::*;
+
+    let mut w = bochscpu_mem_walk_t {
+        entry_gpa: [0; 5],
+        entry: [0; 5],
+        gpa: 0,
+        page_size: 0,
+        user: true,
+        writable: true,
+        nx: false,
+        fault_level: BOCHSCPU_MEM_LEVEL_NONE,
+        error: BOCHSCPU_ERROR_NONE,
+    };
+
+    let levels = if la57 { &LEVELS[..] } else { &LEVELS[1..] };
+
+    let canonical = if la57 {
+        is_canonical_la57(gva)
+    } else {
+        is_canonical(gva)
+    };
+    if !canonical {
+        w.fault_level = levels[0];
+        w.error = BOCHSCPU_ERROR_NON_CANONICAL;
+        return w;
+    }
+
+    let mut table = cr3;
+
+    for &level in levels {
+        let addr = (table & PTE_ADDR_MASK) + ((gva >> level.shift()) & 0x1ff) * 8;
+        w.entry_gpa[level as usize] = addr;
+
+        let entry = match unsafe { read_entry(addr) } {
+            Ok(entry) => entry,
+            Err(e) => {
+                w.fault_level = level;
+                w.error = e;
+                return w;
+            }
+        };
+        w.entry[level as usize] = entry;
+
+        if entry & PTE_PRESENT == 0 {
+            w.fault_level = level;
+            w.error = level.not_present();
+            return w;
+        }
+
+        w.user &= entry & PTE_USER != 0;
+        w.writable &= entry & PTE_WRITE != 0;
+        w.nx |= entry & PTE_NX != 0;
+
+        match classify(level, entry) {
+            Err(e) => {
+                w.fault_level = level;
+                w.error = e;
+                return w;
+            }
+            Ok(Some(size)) => {
+                w.page_size = size;
+                w.gpa = (entry & PTE_ADDR_MASK & !(size - 1)) | (gva & (size - 1));
+                return w;
+            }
+            Ok(None) => (),
+        }
+
+        table = entry;
+    }
+
+    unreachable!("page walk did not terminate at the PT level")
+}
+
+/// A range of GVAs mapped to contiguous GPAs with the same attributes
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct bochscpu_mem_region_t {
+    /// First GVA of the region
+    pub gva: u64,
+    /// GPA `gva` translates to
+    pub gpa: u64,
+    /// Length of the region in bytes
+    pub len: u64,
+    /// Size of the pages mapping the region, 4K, 2M or 1G
+    pub page_size: u64,
+    /// Effective U/S, the region is accessible from user mode
+    pub user: bool,
+    /// Effective R/W, the region is writable
+    pub writable: bool,
+    /// Effective XD, the region is not executable
+    pub nx: bool,
+}
+
+impl bochscpu_mem_region_t {
+    fn extend(&mut self, next: &Self) -> bool {
+        let contiguous = self.gva.wrapping_add(self.len) == next.gva
+            && self.gpa.wrapping_add(self.len) == next.gpa;
+        let same = (self.page_size, self.user, self.writable, self.nx)
+            == (next.page_size, next.user, next.writable, next.nx);
+
+        if contiguous && same {
+            self.len += next.len;
+        }
+
+        contiguous && same
+    }
+}
+
+struct Enumerate<F> {
+    la57: bool,
+    pending: Option<bochscpu_mem_region_t>,
+    f: F,
+}
+
+impl<F: FnMut(&bochscpu_mem_region_t) -> bool> Enumerate<F> {
+    fn emit(&mut self, r: bochscpu_mem_region_t) -> ControlFlow<()> {
+        if let Some(p) = &mut self.pending
+            && p.extend(&r)
+        {
+            return ControlFlow::Continue(());
+        }
+
+        match self.pending.replace(r) {
+            Some(p) if !(self.f)(&p) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    unsafe fn table(
+        &mut self,
+        table: u64,
+        levels: &[bochscpu_mem_level_t],
+        base: u64,
+        parent: (bool, bool, bool),
+    ) -> ControlFlow<()> {
+        let Some((&level, rest)) = levels.split_first() else {
+            return ControlFlow::Continue(());
+        };
+
+        // a table which is not backed maps nothing the cpu could reach
+        let mut buf = [0u8; PAGE_SIZE as usize];
+        if unsafe { read_table(table & PTE_ADDR_MASK, &mut buf) }.is_err() {
+            return ControlFlow::Continue(());
+        }
+
+        for (idx, e) in buf.chunks_exact(8).enumerate() {
+            let entry = u64::from_le_bytes(e.try_into().unwrap());
+            if entry & PTE_PRESENT == 0 {
+                continue;
+            }
+
+            let gva = base | (idx as u64) << level.shift();
+            let (user, writable, nx) = (
+                parent.0 && entry & PTE_USER != 0,
+                parent.1 && entry & PTE_WRITE != 0,
+                parent.2 || entry & PTE_NX != 0,
+            );
+
+            match classify(level, entry) {
+                // the cpu would fault, so nothing is mapped here
+                Err(_) => (),
+                Ok(Some(size)) => {
+                    // sign extend from the top translated bit
+                    let unused = if self.la57 { 7 } else { 16 };
+                    let gva = (((gva << unused) as i64) >> unused) as u64;
+
+                    self.emit(bochscpu_mem_region_t {
+                        gva,
+                        gpa: entry & PTE_ADDR_MASK & !(size - 1),
+                        len: size,
+                        page_size: size,
+                        user,
+                        writable,
+                        nx,
+                    })?;
+                }
+                Ok(None) => unsafe { self.table(entry, rest, gva, (user, writable, nx))? },
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+/// Enumerate every mapping in the page tables rooted at cr3
+///
+/// Calls `f` with each region of contiguous mappings, in ascending GVA order
+/// with the lower half first, stopping early if `f` returns false. Entries
+/// which would fault are skipped, as are paging structures in pages which
+/// are not present.
+pub(crate) unsafe fn enumerate(
+    cr3: u64,
+    la57: bool,
+    f: impl FnMut(&bochscpu_mem_region_t) -> bool,
+) {
+    let levels = if la57 { &LEVELS[..] } else { &LEVELS[1..] };
+
+    let mut e = Enumerate {
+        la57,
+        pending: None,
+        f,
+    };
+
+    if unsafe { e.table(cr3, levels, 0, (true, true, false)) }.is_continue()
+        && let Some(p) = e.pending.take()
+    {
+        (e.f)(&p);
+    }
+}
+
+fn virt_mem_error(e: VirtMemError) -> bochscpu_error_t {
+    use bochscpu_error_t::*;
+
+    match e {
+        VirtMemError::Pml4eNotPresent => BOCHSCPU_ERROR_PML4E_NOT_PRESENT,
+        VirtMemError::PdpteNotPresent => BOCHSCPU_ERROR_PDPTE_NOT_PRESENT,
+        VirtMemError::PdeNotPresent => BOCHSCPU_ERROR_PDE_NOT_PRESENT,
+        VirtMemError::PteNotPresent => BOCHSCPU_ERROR_PTE_NOT_PRESENT,
+        VirtMemError::SpanningPage => BOCHSCPU_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+/// Translate a GVA to a GPA using the 4-level page tables rooted at cr3
+///
+/// The tables are checked with `walk()` first, so failures are reported the
+/// same way as by `bochscpu_mem_virt_walk()`, then the translation is done by
+/// the core, the same way as for guest accesses. Access permissions are not
+/// checked.
+pub(crate) fn translate(cr3: u64, gva: u64) -> Result<u64, bochscpu_error_t> {
+    let gpa = unsafe { walk(cr3, gva, false) }.result()?;
+
+    // every paging structure the core reads was found present by the walk,
+    // so the core panicking here is a bug rather than a missing page
+    match panic::catch_unwind(|| virt_translate_checked(cr3, gva)) {
+        Ok(r) => {
+            let r = r.map_err(virt_mem_error);
+            debug_assert_eq!(r, Ok(gpa), "page walk disagrees with the core");
+            r
+        }
+        Err(_) => Err(bochscpu_error_t::BOCHSCPU_ERROR_PANIC),
+    }
+}
+
+/// Translate a GVA whose data the library is about to access
+///
+/// Same as `translate()`, and also faults in the page it maps to, so a
+/// missing page handler aborting is reported rather than left to the core.
+pub(crate) fn translate_data(cr3: u64, gva: u64) -> Result<u64, bochscpu_error_t> {
+    let gpa = translate(cr3, gva)?;
+
+    mem::fault_in(gpa, 1).map_err(|(kind, _)| kind)?;
+
+    Ok(gpa)
+}
+
+/// Same as `translate_access()`, faulting in the data page as `translate_data()`
+pub(crate) unsafe fn translate_access_data(
+    cr3: u64,
+    gva: u64,
+    a: &bochscpu_mem_access_t,
+) -> Result<u64, Fault> {
+    let gpa = unsafe { translate_access(cr3, gva, a)? };
+
+    mem::fault_in(gpa, 1).map_err(|(kind, _)| (kind, None))?;
+
+    Ok(gpa)
+}
+
+/// Translate every page in `[gva, gva + len)` with `f`
+///
+/// The returned chunks hold GPAs. On failure returns the GVA which could not
+/// be translated along with the error from `f`.
+pub(crate) fn translate_range<E>(
+    gva: u64,
+    len: usize,
+    mut f: impl FnMut(u64) -> Result<u64, E>,
+) -> Result<Vec<Chunk>, (u64, E)> {
+    page_chunks(gva, len)
+        .map(|(va, off, sz)| match f(va) {
+            Ok(pa) => Ok((pa, off, sz)),
+            Err(e) => Err((va, e)),
+        })
+        .collect()
+}
+
+/// Read translated chunks into `buf`
+pub(crate) unsafe fn read_chunks(chunks: &[Chunk], buf: &mut [u8]) {
+    for &(pa, off, sz) in chunks {
+        unsafe { phy_read_slice(pa, &mut buf[off..off + sz]) };
+    }
+}
+
+/// Write `buf` to translated chunks
+pub(crate) unsafe fn write_chunks(chunks: &[Chunk], buf: &[u8]) {
+    for &(pa, off, sz) in chunks {
+        unsafe { phy_write(pa, &buf[off..off + sz]) };
+        mem::wrote(pa, sz);
+    }
+}
+
+/// Read from GVA
+///
+/// Every page is translated before any memory is touched, so on failure the
+/// buffer is left untouched.
+pub(crate) unsafe fn virt_read(
+    cr3: u64,
+    gva: u64,
+    buf: &mut [u8],
+) -> Result<(), (u64, bochscpu_error_t)> {
+    let chunks = translate_range(gva, buf.len(), |va| translate_data(cr3, va))?;
+
+    unsafe { read_chunks(&chunks, buf) };
+
+    Ok(())
+}
+
+/// Write to GVA
+///
+/// Every page is translated before any memory is touched, so on failure guest
+/// memory is left untouched.
+pub(crate) unsafe fn virt_write(
+    cr3: u64,
+    gva: u64,
+    buf: &[u8],
+) -> Result<(), (u64, bochscpu_error_t)> {
+    let chunks = translate_range(gva, buf.len(), |va| translate_data(cr3, va))?;
+
+    unsafe { write_chunks(&chunks, buf) };
+
+    Ok(())
+}
+
+/// Read from GVA, stopping at the first page which fails to translate
+///
+/// Returns the number of bytes read, along with the failing GVA and error if
+/// the read stopped early.
+pub(crate) unsafe fn virt_read_partial(
+    cr3: u64,
+    gva: u64,
+    buf: &mut [u8],
+) -> (usize, Option<(u64, bochscpu_error_t)>) {
+    for (va, off, sz) in page_chunks(gva, buf.len()) {
+        match translate_data(cr3, va) {
+            Ok(pa) => unsafe { phy_read_slice(pa, &mut buf[off..off + sz]) },
+            Err(e) => return (off, Some((va, e))),
+        }
+    }
+
+    (buf.len(), None)
+}
+
+/// Write to GVA, stopping at the first page which fails to translate
+///
+/// Returns the number of bytes written, along with the failing GVA and error
+/// if the write stopped early.
+pub(crate) unsafe fn virt_write_partial(
+    cr3: u64,
+    gva: u64,
+    buf: &[u8],
+) -> (usize, Option<(u64, bochscpu_error_t)>) {
+    for (va, off, sz) in page_chunks(gva, buf.len()) {
+        match translate_data(cr3, va) {
+            Ok(pa) => unsafe { write_chunks(&[(pa, off, sz)], buf) },
+            Err(e) => return (off, Some((va, e))),
+        }
+    }
+
+    (buf.len(), None)
+}
+
+pub const BOCHSCPU_EXCEPTION_GP: u32 = 13;
+pub const BOCHSCPU_EXCEPTION_PF: u32 = 14;
+
+pub const BOCHSCPU_PF_PRESENT: u32 = 1 << 0;
+pub const BOCHSCPU_PF_WRITE: u32 = 1 << 1;
+pub const BOCHSCPU_PF_USER: u32 = 1 << 2;
+pub const BOCHSCPU_PF_RSVD: u32 = 1 << 3;
+pub const BOCHSCPU_PF_FETCH: u32 = 1 << 4;
+
+/// Description of a virtual memory access
+///
+/// `access` is one of the `BOCHSCPU_HOOK_MEM_*` constants, with
+/// `BOCHSCPU_HOOK_MEM_RW` checked as a write. The remaining fields mirror the cpu state
+/// which affects permission checks, and can be filled in from a cpu with
+/// `bochscpu_cpu_mem_access()`.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct bochscpu_mem_access_t {
+    pub access: u32,
+    /// The access is made from user mode (CPL 3)
+    pub user: bool,
+    /// CR0.WP, supervisor writes honor read-only pages
+    pub wp: bool,
+    /// CR4.SMEP, supervisor fetches from user pages fault
+    pub smep: bool,
+    /// CR4.SMAP, supervisor data accesses to user pages fault
+    pub smap: bool,
+    /// RFLAGS.AC, suppresses SMAP for supervisor data accesses
+    pub ac: bool,
+    /// EFER.NXE, the XD bit is honored rather than reserved
+    pub nxe: bool,
+    /// CR4.LA57, 5-level paging is in use
+    pub la57: bool,
+}
+
+/// Exception raised by a faulting memory access
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct bochscpu_mem_fault_t {
+    /// `BOCHSCPU_EXCEPTION_PF` or `BOCHSCPU_EXCEPTION_GP`
+    pub vector: u32,
+    /// Error code pushed by the exception, a combination of `BOCHSCPU_PF_*`
+    /// for page faults
+    pub error_code: u32,
+    /// Faulting GVA, the value cr2 would take for a page fault
+    pub address: u64,
+}
+
+/// Failure of a permission checked access
+///
+/// The exception is None if the failure is not one the cpu would raise an
+/// exception for, such as a paging structure in a page which is not present.
+pub(crate) type Fault = (bochscpu_error_t, Option<bochscpu_mem_fault_t>);
+
+/// Translate a GVA, checking `a` is permitted the way the cpu would
+pub(crate) unsafe fn translate_access(
+    cr3: u64,
+    gva: u64,
+    a: &bochscpu_mem_access_t,
+) -> Result<u64, Fault> {
+    use bochscpu_error_t::*;
+
+    let w = unsafe { walk(cr3, gva, a.la57) };
+
+    let kind = bochscpu_hook_mem_t::from_raw(a.access);
+    let write = matches!(kind, Some(BOCHSCPU_HOOK_MEM_WRITE | BOCHSCPU_HOOK_MEM_RW));
+    let fetch = kind == Some(BOCHSCPU_HOOK_MEM_EXECUTE);
+
+    let mut error_code = 0;
+    if write {
+        error_code |= BOCHSCPU_PF_WRITE;
+    }
+    if a.user {
+        error_code |= BOCHSCPU_PF_USER;
+    }
+    if fetch && (a.nxe || a.smep) {
+        error_code |= BOCHSCPU_PF_FETCH;
+    }
+
+    let pf = |kind, error_code| {
+        Err((
+            kind,
+            Some(bochscpu_mem_fault_t {
+                vector: BOCHSCPU_EXCEPTION_PF,
+                error_code,
+                address: gva,
+            }),
+        ))
+    };
+
+    match w.error {
+        BOCHSCPU_ERROR_NONE => (),
+        BOCHSCPU_ERROR_NON_CANONICAL => {
+            return Err((
+                BOCHSCPU_ERROR_NON_CANONICAL,
+                Some(bochscpu_mem_fault_t {
+                    vector: BOCHSCPU_EXCEPTION_GP,
+                    error_code: 0,
+                    address: gva,
+                }),
+            ));
+        }
+        BOCHSCPU_ERROR_RESERVED_BIT => {
+            return pf(
+                BOCHSCPU_ERROR_RESERVED_BIT,
+                error_code | BOCHSCPU_PF_PRESENT | BOCHSCPU_PF_RSVD,
+            );
+        }
+        // the tables could not be read, the cpu would not get this far either
+        e @ (BOCHSCPU_ERROR_PAGE_NOT_PRESENT | BOCHSCPU_ERROR_INVALID_ARGUMENT) => {
+            return Err((e, None));
+        }
+        e => return pf(e, error_code),
+    }
+
+    // without EFER.NXE the XD bit is reserved
+    if w.nx && !a.nxe {
+        return pf(
+            BOCHSCPU_ERROR_RESERVED_BIT,
+            error_code | BOCHSCPU_PF_PRESENT | BOCHSCPU_PF_RSVD,
+        );
+    }
+
+    let denied = if a.user {
+        !w.user || (write && !w.writable) || (fetch && w.nx)
+    } else if fetch {
+        w.nx || (a.smep && w.user)
+    } else {
+        (a.smap && !a.ac && w.user) || (write && a.wp && !w.writable)
+    };
+
+    if denied {
+        return pf(
+            BOCHSCPU_ERROR_ACCESS_VIOLATION,
+            error_code | BOCHSCPU_PF_PRESENT,
+        );
+    }
+
+    Ok(w.gpa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt::{
+        BOCHSCPU_PT_PAGE_2M, BOCHSCPU_PT_USER, BOCHSCPU_PT_WRITE, bochscpu_pt_builder_cr3,
+        bochscpu_pt_builder_delete, bochscpu_pt_builder_map,
+    };
+    use crate::testutil::{builder, lock, map, page, w64};
+
+    #[test]
+    fn translate_errors() {
+        let _l = lock();
+        page(0x10_5000);
+
+        // the PML4, PDPT, PD and PT are at 0x10_1000 to 0x10_4000
+        let b = builder(0x10_1000, 4, false);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        let gva = 0x7fff_0000_1000;
+        map(b, gva, 0x10_5000, BOCHSCPU_PT_WRITE | BOCHSCPU_PT_USER);
+        unsafe { bochscpu_pt_builder_delete(b) };
+
+        assert_eq!(translate(cr3, gva + 0x10), Ok(0x10_5010));
+        assert_eq!(
+            translate(cr3, gva + 0x1000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_PTE_NOT_PRESENT)
+        );
+        assert_eq!(
+            translate(cr3, 0x8000_0000_0000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_NON_CANONICAL)
+        );
+
+        // a PT which was never inserted
+        w64(0x10_3000 + ((gva >> 21) & 0x1ff) * 8 + 8, 0x10_f000 | 7);
+        assert_eq!(
+            translate(cr3, gva + 0x20_0000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_PAGE_NOT_PRESENT)
+        );
+        let w = unsafe { walk(cr3, gva + 0x20_0000, false) };
+        assert_eq!(w.fault_level, bochscpu_mem_level_t::BOCHSCPU_MEM_LEVEL_PT);
+
+        // a 2M page with bit 13 set, which the core would not notice
+        w64(0x10_3000 + ((gva >> 21) & 0x1ff) * 8 + 16, 0x20_2000 | 0x87);
+        assert_eq!(
+            translate(cr3, gva + 0x40_0000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT)
+        );
+        let w = unsafe { walk(cr3, gva + 0x40_0000, false) };
+        assert_eq!(w.error, bochscpu_error_t::BOCHSCPU_ERROR_RESERVED_BIT);
+    }
+
+    #[test]
+    fn enumerate_skips_missing() {
+        let _l = lock();
+        page(0x10_c000);
+
+        // the PML4, PDPT, PD and PT are at 0x10_8000 to 0x10_b000
+        let b = builder(0x10_8000, 4, false);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        let flags = BOCHSCPU_PT_WRITE | BOCHSCPU_PT_USER;
+        map(b, 0x1000, 0x10_c000, flags);
+        unsafe {
+            let status = bochscpu_pt_builder_map(
+                b,
+                0x40_0000,
+                0x20_0000,
+                0x20_0000,
+                BOCHSCPU_PT_PAGE_2M,
+                flags,
+            );
+            assert_eq!(status, crate::error::bochscpu_status_t::BOCHSCPU_STATUS_OK);
+            bochscpu_pt_builder_delete(b);
+        }
+        // a PT which was never inserted, between the two
+        w64(0x10_a000 + 8, 0x1f_f000 | 7);
+
+        let mut regions = Vec::new();
+        unsafe {
+            enumerate(cr3, false, |r| {
+                regions.push((r.gva, r.gpa, r.len));
+                true
+            })
+        };
+
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x10_c000, 0x1000),
+                (0x40_0000, 0x20_0000, 0x20_0000)
+            ]
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use std::ffi::c_void;
+use std::ptr;
+
+use bochscpu::mem::phy_write;
+
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard, guard_or, set_last_error};
+use crate::mem;
+use crate::paging::{
+    LEVELS, PAGE_SIZE, PTE_ADDR_MASK, PTE_LARGE, PTE_NX, PTE_PRESENT, PTE_USER, PTE_WRITE,
+    bochscpu_mem_level_t, is_canonical, is_canonical_la57, read_entry,
+};
+
+#[allow(non_camel_case_types)]
+pub type bochscpu_pt_builder_t = *mut c_void;
+
+/// Allocator for paging structure pages
+///
+/// Returns the GPA of a free page aligned page, or -1 if no memory is
+/// available. The page must be backed, either already inserted or provided by
+/// the missing page handler.
+#[allow(non_camel_case_types)]
+pub type bochscpu_pt_alloc_t = extern "C" fn(ctx: *mut c_void) -> u64;
+
+pub const BOCHSCPU_PT_WRITE: u64 = 1 << 1;
+const_assert_eq!(BOCHSCPU_PT_WRITE, PTE_WRITE);
+
+pub const BOCHSCPU_PT_USER: u64 = 1 << 2;
+const_assert_eq!(BOCHSCPU_PT_USER, PTE_USER);
+
+pub const BOCHSCPU_PT_NX: u64 = 0x8000_0000_0000_0000;
+const_assert_eq!(BOCHSCPU_PT_NX, PTE_NX);
+
+const PT_FLAGS: u64 = BOCHSCPU_PT_WRITE | BOCHSCPU_PT_USER | BOCHSCPU_PT_NX;
+
+pub const BOCHSCPU_PT_PAGE_4K: u64 = 0x1000;
+pub const BOCHSCPU_PT_PAGE_2M: u64 = 0x20_0000;
+pub const BOCHSCPU_PT_PAGE_1G: u64 = 0x4000_0000;
+
+type Error = (bochscpu_error_t, String);
+
+struct PtBuilder {
+    cr3: u64,
+    la57: bool,
+    alloc: bochscpu_pt_alloc_t,
+    ctx: *mut c_void,
+}
+
+unsafe fn write_entry(gpa: u64, val: u64) {
+    unsafe { phy_write(gpa, &val.to_le_bytes()) };
+    mem::wrote(gpa, 8);
+}
+
+impl PtBuilder {
+    unsafe fn alloc_table(&self) -> Result<u64, Error> {
+        use bochscpu_error_t::*;
+
+        let gpa = (self.alloc)(self.ctx);
+        if gpa == 0xffff_ffff_ffff_ffff {
+            return Err((
+                BOCHSCPU_ERROR_ALLOCATION,
+                "page table allocator is out of memory".into(),
+            ));
+        }
+
+        if gpa & (PAGE_SIZE - 1) != 0 {
+            return Err((
+                BOCHSCPU_ERROR_UNALIGNED,
+                format!("page table allocator returned unaligned gpa {:#x}", gpa),
+            ));
+        }
+
+        unsafe { phy_write(gpa, &[0; PAGE_SIZE as usize]) };
+        mem::wrote(gpa, PAGE_SIZE as usize);
+
+        Ok(gpa)
+    }
+
+    unsafe fn map_page(
+        &self,
+        gva: u64,
+        gpa: u64,
+        leaf: bochscpu_mem_level_t,
+        flags: u64,
+    ) -> Result<(), Error> {
+        use bochscpu_error_t::*;
+        use bochscpu_mem_level_t::*;
+
+        let levels = if self.la57 { &LEVELS[..] } else { &LEVELS[1..] };
+
+        let mut table = self.cr3;
+        for &level in levels {
+            let entry_gpa = table + ((gva >> level.shift()) & 0x1ff) * 8;
+            let entry = unsafe { read_entry(entry_gpa) }.map_err(|e| {
+                (
+                    e,
+                    format!(
+                        "paging structure entry at gpa {:#x} is not present",
+                        entry_gpa
+                    ),
+                )
+            })?;
+            let present = entry & PTE_PRESENT != 0;
+            let large = level != BOCHSCPU_MEM_LEVEL_PT && entry & PTE_LARGE != 0;
+
+            if level == leaf {
+                // replacing a table would orphan everything it maps
+                if present && level != BOCHSCPU_MEM_LEVEL_PT && !large {
+                    return Err((
+                        BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                        format!("gva {:#x} is already mapped by smaller pages", gva),
+                    ));
+                }
+
+                let mut val = gpa | flags | PTE_PRESENT;
+                if level != BOCHSCPU_MEM_LEVEL_PT {
+                    val |= PTE_LARGE;
+                }
+
+                unsafe { write_entry(entry_gpa, val) };
+
+                return Ok(());
+            }
+
+            if large {
+                return Err((
+                    BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                    format!("gva {:#x} is already mapped by a larger page", gva),
+                ));
+            }
+
+            table = if present {
+                entry & PTE_ADDR_MASK
+            } else {
+                let t = unsafe { self.alloc_table()? };
+                // leaf entries decide the permissions
+                unsafe { write_entry(entry_gpa, t | PTE_PRESENT | PTE_WRITE | PTE_USER) };
+                t
+            };
+        }
+
+        unreachable!("walked past the leaf level");
+    }
+
+    unsafe fn map(
+        &self,
+        gva: u64,
+        gpa: u64,
+        len: u64,
+        page_size: u64,
+        flags: u64,
+    ) -> Result<(), Error> {
+        use bochscpu_error_t::*;
+        use bochscpu_mem_level_t::*;
+
+        let leaf = match page_size {
+            BOCHSCPU_PT_PAGE_4K => BOCHSCPU_MEM_LEVEL_PT,
+            BOCHSCPU_PT_PAGE_2M => BOCHSCPU_MEM_LEVEL_PD,
+            BOCHSCPU_PT_PAGE_1G => BOCHSCPU_MEM_LEVEL_PDPT,
+            _ => {
+                return Err((
+                    BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                    format!("unsupported page size {:#x}", page_size),
+                ));
+            }
+        };
+
+        if flags & !PT_FLAGS != 0 {
+            return Err((
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                format!("unsupported mapping flags {:#x}", flags),
+            ));
+        }
+
+        if (gva | gpa | len) & (page_size - 1) != 0 {
+            return Err((
+                BOCHSCPU_ERROR_UNALIGNED,
+                format!(
+                    "gva {:#x} -> gpa {:#x} length {:#x} is not aligned to {:#x}",
+                    gva, gpa, len, page_size
+                ),
+            ));
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let canonical = if self.la57 {
+            is_canonical_la57
+        } else {
+            is_canonical
+        };
+        let last = gva.checked_add(len - 1);
+        let in_range = match last {
+            Some(last) => canonical(gva) && canonical(last) && gva >> 63 == last >> 63,
+            None => false,
+        };
+        if !in_range {
+            return Err((
+                BOCHSCPU_ERROR_NON_CANONICAL,
+                format!("gva {:#x} length {:#x} is not a canonical range", gva, len),
+            ));
+        }
+
+        // entries hold 52-bit physical addresses
+        let gpa_last = gpa.checked_add(len - 1);
+        if gpa_last.is_none_or(|last| last >> 52 != 0) {
+            return Err((
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                format!("gpa {:#x} length {:#x} is not addressable", gpa, len),
+            ));
+        }
+
+        let mut off = 0;
+        while off < len {
+            unsafe { self.map_page(gva + off, gpa + off, leaf, flags)? };
+            off += page_size;
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a page table builder
+///
+/// Paging structure pages are allocated by calling `alloc` with `ctx`, and are
+/// zeroed before use. The root table is allocated immediately, use
+/// `bochscpu_pt_builder_cr3()` to retrieve it. Builds 5-level page tables if
+/// `la57` is set, 4-level page tables otherwise.
+///
+/// # Returns
+///
+/// A builder which must be freed with `bochscpu_pt_builder_delete()`, or NULL
+/// on failure. The reason for a failure can be retrieved with
+/// `bochscpu_last_error()`.
+///
+/// # Safety
+///
+/// `alloc` is called with `ctx` until the builder is deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_pt_builder_new(
+    la57: bool,
+    alloc: bochscpu_pt_alloc_t,
+    ctx: *mut c_void,
+) -> bochscpu_pt_builder_t {
+    guard_or(ptr::null_mut(), || unsafe {
+        let mut b = PtBuilder {
+            cr3: 0,
+            la57,
+            alloc,
+            ctx,
+        };
+
+        match b.alloc_table() {
+            Ok(cr3) => {
+                b.cr3 = cr3;
+                Box::into_raw(Box::new(b)) as _
+            }
+            Err((kind, msg)) => {
+                set_last_error(kind, &msg);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Map a GVA range to a GPA range
+///
+/// Maps `len` bytes at `gva` to `gpa` using pages of `page_size`, one of the
+/// `BOCHSCPU_PT_PAGE_*` constants. `gva`, `gpa` and `len` must all be aligned
+/// to `page_size`. `flags` is a combination of `BOCHSCPU_PT_WRITE`,
+/// `BOCHSCPU_PT_USER` and `BOCHSCPU_PT_NX`, pages are mapped read-only,
+/// supervisor and executable otherwise.
+///
+/// Existing mappings of the same page size are replaced. Intermediate paging
+/// structures are created as needed and do not restrict permissions.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the arguments are invalid, part of the range is
+/// already mapped with a different page size, or the allocator fails. Pages
+/// mapped before an allocation failure stay mapped.
+///
+/// # Safety
+///
+/// `p` must be a builder returned by `bochscpu_pt_builder_new()` which has not
+/// been deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_pt_builder_map(
+    p: bochscpu_pt_builder_t,
+    gva: u64,
+    gpa: u64,
+    len: u64,
+    page_size: u64,
+    flags: u64,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let b = &*(p as *const PtBuilder);
+
+        match b.map(gva, gpa, len, page_size, flags) {
+            Ok(()) => bochscpu_status_t::BOCHSCPU_STATUS_OK,
+            Err((kind, msg)) => fail(kind, &msg),
+        }
+    })
+}
+
+/// Get the cr3 of the page tables being built
+///
+/// # Safety
+///
+/// `p` must be a builder returned by `bochscpu_pt_builder_new()` which has not
+/// been deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_pt_builder_cr3(p: bochscpu_pt_builder_t) -> u64 {
+    guard_or(0, || unsafe { (*(p as *const PtBuilder)).cr3 })
+}
+
+/// Delete a page table builder
+///
+/// The page tables themselves are left in guest memory.
+///
+/// # Safety
+///
+/// `p` must be a builder returned by `bochscpu_pt_builder_new()` which has not
+/// been deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_pt_builder_delete(p: bochscpu_pt_builder_t) -> bochscpu_status_t {
+    guard(|| unsafe {
+        drop(Box::from_raw(p as *mut PtBuilder));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::bochscpu_last_error;
+    use crate::paging::{bochscpu_mem_walk_t, translate, walk};
+    use crate::testutil::{builder, lock};
+
+    fn walk_ok(cr3: u64, gva: u64, la57: bool) -> bochscpu_mem_walk_t {
+        let w = unsafe { walk(cr3, gva, la57) };
+        assert_eq!(w.error, bochscpu_error_t::BOCHSCPU_ERROR_NONE);
+
+        w
+    }
+
+    fn map(b: bochscpu_pt_builder_t, gva: u64, gpa: u64, len: u64, size: u64, flags: u64) {
+        let status = unsafe { bochscpu_pt_builder_map(b, gva, gpa, len, size, flags) };
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+    }
+
+    fn map_err(b: bochscpu_pt_builder_t, gva: u64, size: u64) -> bochscpu_error_t {
+        let status = unsafe { bochscpu_pt_builder_map(b, gva, 0, size, size, 0) };
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+
+        bochscpu_last_error()
+    }
+
+    #[test]
+    fn four_level() {
+        let _l = lock();
+        let b = builder(0xf0_0000, 8, false);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+
+        let rw = BOCHSCPU_PT_WRITE | BOCHSCPU_PT_USER;
+        map(
+            b,
+            0x7fff_0000_0000,
+            0x1234_5000,
+            0x2000,
+            BOCHSCPU_PT_PAGE_4K,
+            rw,
+        );
+        map(
+            b,
+            0x4020_0000,
+            0x8000_0000,
+            0x20_0000,
+            BOCHSCPU_PT_PAGE_2M,
+            BOCHSCPU_PT_NX,
+        );
+        map(
+            b,
+            0xffff_8000_0000_0000,
+            0x4000_0000,
+            0x4000_0000,
+            BOCHSCPU_PT_PAGE_1G,
+            0,
+        );
+
+        let w = walk_ok(cr3, 0x7fff_0000_1010, false);
+        assert_eq!((w.gpa, w.page_size), (0x1234_6010, 0x1000));
+        assert!(w.user && w.writable && !w.nx);
+        assert_eq!(translate(cr3, 0x7fff_0000_1010), Ok(0x1234_6010));
+
+        let w = walk_ok(cr3, 0x4021_2345, false);
+        assert_eq!((w.gpa, w.page_size), (0x8001_2345, 0x20_0000));
+        assert!(!w.user && !w.writable && w.nx);
+        assert_eq!(translate(cr3, 0x4021_2345), Ok(0x8001_2345));
+
+        let w = walk_ok(cr3, 0xffff_8000_1234_5678, false);
+        assert_eq!((w.gpa, w.page_size), (0x5234_5678, 0x4000_0000));
+        assert_eq!(translate(cr3, 0xffff_8000_1234_5678), Ok(0x5234_5678));
+
+        // the pages around the mappings are left unmapped
+        assert_eq!(
+            translate(cr3, 0x7fff_0000_2000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_PTE_NOT_PRESENT)
+        );
+        assert_eq!(
+            translate(cr3, 0x4000_0000),
+            Err(bochscpu_error_t::BOCHSCPU_ERROR_PDE_NOT_PRESENT)
+        );
+
+        unsafe { bochscpu_pt_builder_delete(b) };
+    }
+
+    #[test]
+    fn five_level() {
+        let _l = lock();
+        let b = builder(0xf1_0000, 8, true);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        let gva = 0x00ff_8000_0000_3000;
+
+        map(
+            b,
+            gva,
+            0x5000,
+            0x1000,
+            BOCHSCPU_PT_PAGE_4K,
+            BOCHSCPU_PT_WRITE,
+        );
+
+        let w = walk_ok(cr3, gva + 8, true);
+        assert_eq!((w.gpa, w.page_size), (0x5008, 0x1000));
+        assert_eq!(w.entry_gpa[0], cr3 + ((gva >> 48) & 0x1ff) * 8);
+        assert!(!w.user && w.writable);
+
+        unsafe { bochscpu_pt_builder_delete(b) };
+    }
+
+    #[test]
+    fn size_conflicts() {
+        use bochscpu_error_t::*;
+
+        let _l = lock();
+        let b = builder(0xf2_0000, 8, false);
+
+        map(b, 0x20_0000, 0x20_0000, 0x20_0000, BOCHSCPU_PT_PAGE_2M, 0);
+        map(b, 0x40_0000, 0x40_0000, 0x1000, BOCHSCPU_PT_PAGE_4K, 0);
+
+        // a 4K page inside the 2M page, then a 1G page over both
+        assert_eq!(
+            map_err(b, 0x20_1000, BOCHSCPU_PT_PAGE_4K),
+            BOCHSCPU_ERROR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            map_err(b, 0, BOCHSCPU_PT_PAGE_1G),
+            BOCHSCPU_ERROR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            map_err(b, 0x40_0000, BOCHSCPU_PT_PAGE_2M),
+            BOCHSCPU_ERROR_INVALID_ARGUMENT
+        );
+
+        // the same page size replaces the mapping
+        map(b, 0x20_0000, 0x60_0000, 0x20_0000, BOCHSCPU_PT_PAGE_2M, 0);
+        let cr3 = unsafe { bochscpu_pt_builder_cr3(b) };
+        assert_eq!(translate(cr3, 0x20_0010), Ok(0x60_0010));
+
+        unsafe { bochscpu_pt_builder_delete(b) };
+    }
+
+    #[test]
+    fn allocator_failure() {
+        use bochscpu_error_t::*;
+
+        let _l = lock();
+
+        // no room for the root table
+        assert!(builder(0xf3_0000, 0, false).is_null());
+        assert_eq!(bochscpu_last_error(), BOCHSCPU_ERROR_ALLOCATION);
+
+        // room for the PML4 and PDPT, but not the PD
+        let b = builder(0xf3_0000, 2, false);
+        assert_eq!(
+            map_err(b, 0x1000, BOCHSCPU_PT_PAGE_4K),
+            BOCHSCPU_ERROR_ALLOCATION
+        );
+
+        // 1G pages only need the two
+        map(b, 0x4000_0000, 0, 0x4000_0000, BOCHSCPU_PT_PAGE_1G, 0);
+
+        unsafe { bochscpu_pt_builder_delete(b) };
+    }
+}
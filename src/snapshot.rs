@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bochscpu::cpu::{Cpu, State};
+use bochscpu::mem::phy_write;
+
+use crate::cpu::live_cpus;
+use crate::dirty;
+use crate::error::{bochscpu_status_t, guard, guard_or};
+use crate::mem::{mapped_pages, open_bus};
+use crate::paging::PAGE_SIZE;
+
+#[allow(non_camel_case_types)]
+pub type bochscpu_snapshot_t = *mut c_void;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Snapshot {
+    id: u64,
+    cpus: Vec<(u32, State)>,
+    pages: BTreeMap<u64, Box<[u8]>>,
+}
+
+/// Snapshot the whole machine
+///
+/// Captures the state of every cpu which has been created and not deleted,
+/// and the contents of every page added with `bochscpu_mem_page_insert()`.
+/// Open bus pages inserted for `BOCHSCPU_MEM_MISSING_UNRESOLVED` are left out.
+///
+/// Dirty tracking is turned on, as by `bochscpu_mem_dirty_tracking()`, so
+/// that restoring this snapshot only has to look at the pages written since.
+///
+/// # Returns
+///
+/// A snapshot which must be freed with `bochscpu_snapshot_delete()`, or NULL
+/// on failure.
+///
+/// # Safety
+///
+/// The HVAs of inserted pages must still be valid for reads.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_snapshot_take() -> bochscpu_snapshot_t {
+    guard_or(ptr::null_mut(), || unsafe {
+        let cpus = live_cpus()
+            .into_iter()
+            .map(|id| (id, Cpu::from(id).state()))
+            .collect();
+
+        let open = open_bus();
+        let pages = mapped_pages()
+            .iter()
+            .filter(|(gpa, _)| !open.contains(gpa))
+            .map(|(&gpa, &hva)| {
+                let page = slice::from_raw_parts(hva as *const u8, PAGE_SIZE as usize);
+                (gpa, page.into())
+            })
+            .collect();
+        drop(open);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        dirty::enable();
+        dirty::rebase(id);
+
+        Box::into_raw(Box::new(Snapshot { id, cpus, pages })) as _
+    })
+}
+
+/// Restore a snapshot
+///
+/// Every cpu in the snapshot has its state reloaded, and the pages written
+/// since the snapshot was taken or last restored are copied back. Pages
+/// removed since the snapshot was taken are written through the missing page
+/// handler, pages inserted since are left as is. Restoring does not change
+/// the set reported by `bochscpu_mem_dirty_pages()`.
+///
+/// Writes through an HVA are invisible to dirty tracking, so pages whose HVA
+/// was returned by `bochscpu_mem_phy_translate()` are copied back on every
+/// restore. Writes through the HVA of an inserted page are only undone by the
+/// fallback below.
+///
+/// Only the most recently taken or restored snapshot can rely on dirty
+/// tracking. Restoring any other snapshot, or restoring after tracking was
+/// turned off, falls back to comparing every page in the snapshot against
+/// guest memory, which costs time proportional to the whole snapshot.
+///
+/// # Safety
+///
+/// `p` must be a snapshot returned by `bochscpu_snapshot_take()` which has
+/// not been deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_snapshot_restore(p: bochscpu_snapshot_t) -> bochscpu_status_t {
+    guard(|| unsafe {
+        let snap = &*(p as *const Snapshot);
+
+        for (id, state) in &snap.cpus {
+            Cpu::from(*id).set_state(state);
+        }
+
+        // look up the current mappings up front, writing an unmapped page can
+        // re-enter bochscpu_mem_page_insert from the missing page handler
+        let current: Vec<_> = {
+            let pages = mapped_pages();
+            let lookup = |(gpa, data)| (gpa, data, pages.get(gpa).copied());
+
+            match dirty::changed_since(snap.id) {
+                Some(changed) => changed
+                    .iter()
+                    .filter_map(|gpa| snap.pages.get_key_value(gpa))
+                    .map(lookup)
+                    .collect(),
+                // the fallback, compare against every page
+                None => snap
+                    .pages
+                    .iter()
+                    .map(lookup)
+                    .filter(|(_, data, hva)| match hva {
+                        Some(hva) => {
+                            let page = slice::from_raw_parts(*hva as *const u8, data.len());
+                            ***data != *page
+                        }
+                        None => true,
+                    })
+                    .collect(),
+            }
+        };
+
+        for (&gpa, data, hva) in current {
+            match hva {
+                Some(hva) => {
+                    let page = slice::from_raw_parts_mut(hva as *mut u8, data.len());
+                    page.copy_from_slice(data);
+                }
+                None => phy_write(gpa, data),
+            }
+        }
+
+        dirty::rebase(snap.id);
+    })
+}
+
+/// Delete a snapshot
+///
+/// # Safety
+///
+/// `p` must be a snapshot returned by `bochscpu_snapshot_take()` which has
+/// not been deleted.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_snapshot_delete(p: bochscpu_snapshot_t) -> bochscpu_status_t {
+    guard(|| unsafe {
+        drop(Box::from_raw(p as *mut Snapshot));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::bochscpu_mem_dirty_tracking;
+    use crate::mem::{bochscpu_mem_phy_read, bochscpu_mem_phy_translate, bochscpu_mem_phy_write};
+    use crate::testutil::{lock, page, w64};
+
+    fn r64(gpa: u64) -> u64 {
+        let mut v = [0u8; 8];
+        unsafe { bochscpu_mem_phy_read(gpa, v.as_mut_ptr(), 8) };
+
+        u64::from_le_bytes(v)
+    }
+
+    #[test]
+    fn restore() {
+        let _l = lock();
+        let tracking = dirty::enabled();
+        page(0x70_0000);
+        page(0x70_1000);
+        w64(0x70_0000, 0x1111);
+        w64(0x70_1000, 0x2222);
+
+        unsafe {
+            let snap = bochscpu_snapshot_take();
+            assert!(!snap.is_null());
+
+            let v = 0xdead_u64.to_le_bytes();
+            bochscpu_mem_phy_write(0x70_0000, v.as_ptr(), 8);
+            assert_eq!(r64(0x70_0000), 0xdead);
+
+            assert_eq!(
+                bochscpu_snapshot_restore(snap),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(r64(0x70_0000), 0x1111);
+            assert_eq!(r64(0x70_1000), 0x2222);
+
+            // writes through a translated hva are restored as well
+            let hva = bochscpu_mem_phy_translate(0x70_1000);
+            ptr::write_unaligned(hva as *mut u64, 0xbeef);
+            assert_eq!(
+                bochscpu_snapshot_restore(snap),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(r64(0x70_1000), 0x2222);
+
+            // the fallback finds changes the tracker did not see
+            bochscpu_mem_dirty_tracking(false);
+            bochscpu_mem_phy_write(0x70_1008, v.as_ptr(), 8);
+            assert_eq!(
+                bochscpu_snapshot_restore(snap),
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            );
+            assert_eq!(r64(0x70_1008), 0);
+
+            bochscpu_snapshot_delete(snap);
+            bochscpu_mem_dirty_tracking(tracking);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::ffi::{CStr, CString, c_char};
+use std::fmt::Display;
+
+use crate::cpu::bochscpu_cpu_state_t;
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard, guard_or};
+
+fn serialization_error(e: impl Display) -> bochscpu_status_t {
+    fail(
+        bochscpu_error_t::BOCHSCPU_ERROR_SERIALIZATION,
+        &e.to_string(),
+    )
+}
+
+unsafe fn to_string<E: Display>(
+    s: *const bochscpu_cpu_state_t,
+    out: *mut *mut c_char,
+    f: impl FnOnce(&bochscpu_cpu_state_t) -> Result<String, E>,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let ser = match f(&*s) {
+            Ok(v) => v,
+            Err(e) => return serialization_error(e),
+        };
+
+        match CString::new(ser) {
+            Ok(v) => {
+                *out = v.into_raw();
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err(e) => serialization_error(e),
+        }
+    })
+}
+
+unsafe fn from_str<E: Display>(
+    input: *const c_char,
+    s: *mut bochscpu_cpu_state_t,
+    f: impl FnOnce(&str) -> Result<bochscpu_cpu_state_t, E>,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let input = match CStr::from_ptr(input).to_str() {
+            Ok(v) => v,
+            Err(e) => return serialization_error(e),
+        };
+
+        match f(input) {
+            Ok(v) => {
+                *s = v;
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err(e) => serialization_error(e),
+        }
+    })
+}
+
+/// Serialize a cpu state to YAML
+///
+/// On success `*out` points to a NUL terminated string allocated by the
+/// library, which must be released with `bochscpu_string_free()`.
+///
+/// # Safety
+///
+/// `s` must be valid for reads and `out` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_state_to_yaml(
+    s: *const bochscpu_cpu_state_t,
+    out: *mut *mut c_char,
+) -> bochscpu_status_t {
+    unsafe { to_string(s, out, serde_yaml::to_string) }
+}
+
+/// Deserialize a cpu state from a NUL terminated YAML string
+///
+/// `s` is only written to on success.
+///
+/// # Safety
+///
+/// `yaml` must be a NUL terminated string and `s` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_state_from_yaml(
+    yaml: *const c_char,
+    s: *mut bochscpu_cpu_state_t,
+) -> bochscpu_status_t {
+    unsafe { from_str(yaml, s, |v| serde_yaml::from_str(v)) }
+}
+
+/// Serialize a cpu state to JSON
+///
+/// On success `*out` points to a NUL terminated string allocated by the
+/// library, which must be released with `bochscpu_string_free()`.
+///
+/// # Safety
+///
+/// `s` must be valid for reads and `out` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_state_to_json(
+    s: *const bochscpu_cpu_state_t,
+    out: *mut *mut c_char,
+) -> bochscpu_status_t {
+    unsafe { to_string(s, out, serde_json::to_string_pretty) }
+}
+
+/// Deserialize a cpu state from a NUL terminated JSON string
+///
+/// `s` is only written to on success.
+///
+/// # Safety
+///
+/// `json` must be a NUL terminated string and `s` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_cpu_state_from_json(
+    json: *const c_char,
+    s: *mut bochscpu_cpu_state_t,
+) -> bochscpu_status_t {
+    unsafe { from_str(json, s, |v| serde_json::from_str(v)) }
+}
+
+/// Free a string allocated by the library
+///
+/// Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `p` must be NULL or a string returned by the library which has not been
+/// freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_string_free(p: *mut c_char) -> bochscpu_status_t {
+    guard(|| unsafe {
+        if !p.is_null() {
+            drop(CString::from_raw(p));
+        }
+    })
+}
@@ -0,0 +1,78 @@
+//! Helpers shared by the unit tests
+//!
+//! Guest memory, cpus and the missing page handler are global, and snapshots
+//! capture all of them, so every test takes `lock()` and uses its own GPAs.
+
+use std::ffi::c_void;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::error::bochscpu_status_t;
+use crate::mem::bochscpu_mem_page_insert;
+use crate::pt::{
+    BOCHSCPU_PT_PAGE_4K, bochscpu_pt_builder_map, bochscpu_pt_builder_new, bochscpu_pt_builder_t,
+};
+
+#[repr(C, align(4096))]
+struct Page([u8; 4096]);
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Serialize tests which touch global emulator state
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Insert a zeroed page at `gpa`, returning its HVA
+pub(crate) fn page(gpa: u64) -> *mut u8 {
+    let p = Box::leak(Box::new(Page([0; 4096])));
+
+    unsafe { bochscpu_mem_page_insert(gpa, p.0.as_mut_ptr()) };
+
+    p.0.as_mut_ptr()
+}
+
+/// Write a u64 to GPA
+pub(crate) fn w64(gpa: u64, v: u64) {
+    unsafe { bochscpu::mem::phy_write(gpa, &v.to_le_bytes()) };
+}
+
+/// Tables handed out to a page table builder, inserted as they are allocated
+struct Pool {
+    next: u64,
+    end: u64,
+}
+
+extern "C" fn alloc(ctx: *mut c_void) -> u64 {
+    let pool = unsafe { &mut *(ctx as *mut Pool) };
+    if pool.next == pool.end {
+        return 0xffff_ffff_ffff_ffff;
+    }
+
+    let gpa = pool.next;
+    pool.next += 0x1000;
+    page(gpa);
+
+    gpa
+}
+
+/// Create a page table builder allocating up to `count` tables from the
+/// pages starting at `base`, in order
+///
+/// The root table is at `base`, and with a single mapping the tables below
+/// it follow level by level.
+pub(crate) fn builder(base: u64, count: u64, la57: bool) -> bochscpu_pt_builder_t {
+    let pool = Box::leak(Box::new(Pool {
+        next: base,
+        end: base + count * 0x1000,
+    }));
+
+    unsafe { bochscpu_pt_builder_new(la57, alloc, pool as *mut Pool as _) }
+}
+
+/// Map the 4K page at `gva` to `gpa`
+pub(crate) fn map(b: bochscpu_pt_builder_t, gva: u64, gpa: u64, flags: u64) {
+    let status =
+        unsafe { bochscpu_pt_builder_map(b, gva, gpa, 0x1000, BOCHSCPU_PT_PAGE_4K, flags) };
+
+    assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+}
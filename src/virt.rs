@@ -0,0 +1,385 @@
+use std::ffi::c_char;
+use std::ptr;
+
+use bochscpu::mem::phy_read_slice;
+
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard_or};
+use crate::mem::virt_error;
+use crate::paging::{self, PAGE_SIZE};
+
+type Error = (u64, bochscpu_error_t);
+
+unsafe fn read<const N: usize>(cr3: u64, gva: u64) -> Result<[u8; N], Error> {
+    let mut buf = [0u8; N];
+
+    unsafe { paging::virt_read(cr3, gva, &mut buf)? };
+
+    Ok(buf)
+}
+
+/// Same as `read()`, reusing the translation of the page last read from
+///
+/// `page` holds the GVA and GPA of that page, and is updated when another
+/// page is translated.
+unsafe fn read_cached<const N: usize>(
+    cr3: u64,
+    gva: u64,
+    page: &mut Option<(u64, u64)>,
+) -> Result<[u8; N], Error> {
+    let off = gva & (PAGE_SIZE - 1);
+    if off + N as u64 > PAGE_SIZE {
+        return unsafe { read::<N>(cr3, gva) };
+    }
+
+    let base = gva & !(PAGE_SIZE - 1);
+    let gpa = match *page {
+        Some((va, pa)) if va == base => pa,
+        _ => {
+            let pa = paging::translate_data(cr3, base).map_err(|e| (gva, e))?;
+            *page = Some((base, pa));
+            pa
+        }
+    };
+
+    let mut buf = [0u8; N];
+    unsafe { phy_read_slice(gpa + off, &mut buf) };
+
+    Ok(buf)
+}
+
+unsafe fn read_scalar<const N: usize, T>(
+    cr3: u64,
+    gva: u64,
+    val: *mut T,
+    conv: fn([u8; N]) -> T,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        match read::<N>(cr3, gva) {
+            Ok(b) => {
+                *val = conv(b);
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            Err((va, e)) => {
+                virt_error(e, cr3, va);
+                bochscpu_status_t::BOCHSCPU_STATUS_ERROR
+            }
+        }
+    })
+}
+
+/// Read NUL terminated elements of `N` bytes into `out`
+///
+/// `out` is always NUL terminated, unless it is empty. Returns the number of
+/// elements read, not counting the terminator. Each page is only translated
+/// once.
+unsafe fn read_str<const N: usize, T: Copy + Default + PartialEq>(
+    cr3: u64,
+    gva: u64,
+    out: &mut [T],
+    conv: fn([u8; N]) -> T,
+) -> (usize, bochscpu_status_t) {
+    let Some(max) = out.len().checked_sub(1) else {
+        let status = fail(
+            bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+            "string buffer has no room for a terminator",
+        );
+        return (0, status);
+    };
+
+    let mut n = 0;
+    let mut page = None;
+    let status = loop {
+        let va = gva.wrapping_add((n * N) as u64);
+        let c = match unsafe { read_cached::<N>(cr3, va, &mut page) } {
+            Ok(b) => conv(b),
+            Err((va, e)) => {
+                virt_error(e, cr3, va);
+                break bochscpu_status_t::BOCHSCPU_STATUS_ERROR;
+            }
+        };
+
+        if c == T::default() {
+            break bochscpu_status_t::BOCHSCPU_STATUS_OK;
+        }
+
+        if n == max {
+            break fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_TRUNCATED,
+                &format!("string at gva {:#x} is longer than {} elements", gva, max),
+            );
+        }
+
+        out[n] = c;
+        n += 1;
+    };
+
+    out[n] = T::default();
+
+    (n, status)
+}
+
+/// Read a u8 from GVA
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the GVA could not be translated, the reason can
+/// be retrieved with `bochscpu_last_error()`.
+///
+/// # Safety
+///
+/// `val` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_u8(
+    cr3: u64,
+    gva: u64,
+    val: *mut u8,
+) -> bochscpu_status_t {
+    unsafe { read_scalar(cr3, gva, val, u8::from_le_bytes) }
+}
+
+/// Read a little endian u16 from GVA
+///
+/// Same as `bochscpu_mem_virt_read_u8()`, the value may cross a page.
+///
+/// # Safety
+///
+/// `val` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_u16(
+    cr3: u64,
+    gva: u64,
+    val: *mut u16,
+) -> bochscpu_status_t {
+    unsafe { read_scalar(cr3, gva, val, u16::from_le_bytes) }
+}
+
+/// Read a little endian u32 from GVA
+///
+/// Same as `bochscpu_mem_virt_read_u8()`, the value may cross a page.
+///
+/// # Safety
+///
+/// `val` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_u32(
+    cr3: u64,
+    gva: u64,
+    val: *mut u32,
+) -> bochscpu_status_t {
+    unsafe { read_scalar(cr3, gva, val, u32::from_le_bytes) }
+}
+
+/// Read a little endian u64 from GVA
+///
+/// Same as `bochscpu_mem_virt_read_u8()`, the value may cross a page.
+///
+/// # Safety
+///
+/// `val` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_u64(
+    cr3: u64,
+    gva: u64,
+    val: *mut u64,
+) -> bochscpu_status_t {
+    unsafe { read_scalar(cr3, gva, val, u64::from_le_bytes) }
+}
+
+/// Read a NUL terminated string from GVA
+///
+/// Copies bytes into `buf` until a NUL byte is read, at most `len - 1` of
+/// them, and NUL terminates `buf`. Bytes are copied as is, no encoding is
+/// assumed. The number of bytes copied, not counting the terminator, is
+/// written to `copied` if it is not NULL.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if a GVA could not be translated before the
+/// terminator was found, or with `BOCHSCPU_ERROR_TRUNCATED` if the string
+/// does not fit in `buf`. In both cases `buf` holds the bytes read so far.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` bytes, and `copied` must be NULL
+/// or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_cstr(
+    cr3: u64,
+    gva: u64,
+    buf: *mut c_char,
+    len: usize,
+    copied: *mut usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let out = if len == 0 {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(buf as *mut u8, len)
+        };
+
+        let (n, status) = read_str(cr3, gva, out, u8::from_le_bytes);
+        if !copied.is_null() {
+            *copied = n;
+        }
+
+        status
+    })
+}
+
+/// Read a NUL terminated UTF-16LE string from GVA
+///
+/// Same as `bochscpu_mem_virt_read_cstr()`, with `len` and `copied` counted
+/// in UTF-16 code units. Code units are copied as is, unpaired surrogates are
+/// not rejected.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` code units, and `copied` must be
+/// NULL or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_wstr(
+    cr3: u64,
+    gva: u64,
+    buf: *mut u16,
+    len: usize,
+    copied: *mut usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let out = if len == 0 {
+            &mut []
+        } else {
+            std::slice::from_raw_parts_mut(buf, len)
+        };
+
+        let (n, status) = read_str(cr3, gva, out, u16::from_le_bytes);
+        if !copied.is_null() {
+            *copied = n;
+        }
+
+        status
+    })
+}
+
+/// Follow a linked list in guest memory
+///
+/// Starting from the list head at `head`, reads the next pointer stored at
+/// `link_offset` bytes into the current node and copies it into `nodes`,
+/// until the next pointer is NULL or points back to `head`. For a Windows
+/// `LIST_ENTRY` list pass the address of the head entry and an offset of 0
+/// to follow `Flink`, or 8 to follow `Blink`. The values copied are the next
+/// pointers themselves, so for `LIST_ENTRY` lists they are the addresses of
+/// the embedded entries rather than of the containing structures.
+///
+/// At most `len` nodes are copied. The number copied is written to `count` if
+/// it is not NULL.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if a next pointer could not be read, or with
+/// `BOCHSCPU_ERROR_TRUNCATED` if the list has more than `len` nodes, which
+/// includes lists which loop without returning to `head`.
+///
+/// # Safety
+///
+/// `nodes` must be valid for writes of `len` elements, and `count` must be
+/// NULL or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_mem_virt_read_list(
+    cr3: u64,
+    head: u64,
+    link_offset: u64,
+    nodes: *mut u64,
+    len: usize,
+    count: *mut usize,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        let mut n = 0;
+        let mut cur = head;
+
+        let status = loop {
+            let next = match read::<8>(cr3, cur.wrapping_add(link_offset)) {
+                Ok(b) => u64::from_le_bytes(b),
+                Err((va, e)) => {
+                    virt_error(e, cr3, va);
+                    break bochscpu_status_t::BOCHSCPU_STATUS_ERROR;
+                }
+            };
+
+            if next == 0 || next == head {
+                break bochscpu_status_t::BOCHSCPU_STATUS_OK;
+            }
+
+            if n == len {
+                break fail(
+                    bochscpu_error_t::BOCHSCPU_ERROR_TRUNCATED,
+                    &format!("list at gva {:#x} has more than {} nodes", head, len),
+                );
+            }
+
+            ptr::write(nodes.add(n), next);
+            n += 1;
+            cur = next;
+        };
+
+        if !count.is_null() {
+            *count = n;
+        }
+
+        status
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pt::{BOCHSCPU_PT_USER, BOCHSCPU_PT_WRITE, bochscpu_pt_builder_delete};
+    use crate::testutil::{builder, lock, map, page};
+
+    const CR3: u64 = 0xa0_0000;
+    const GVA: u64 = 0x1000_0ffe;
+
+    fn setup() {
+        page(0xa0_4000);
+        page(0xa0_5000);
+
+        // "abc" crossing into the next page, followed by L"hi"
+        let b = builder(CR3, 4, false);
+        let flags = BOCHSCPU_PT_WRITE | BOCHSCPU_PT_USER;
+        map(b, GVA & !0xfff, 0xa0_4000, flags);
+        map(b, (GVA + 2) & !0xfff, 0xa0_5000, flags);
+        unsafe {
+            bochscpu_pt_builder_delete(b);
+            paging::virt_write(CR3, GVA, b"abc\0h\0i\0\0\0").unwrap();
+        }
+    }
+
+    #[test]
+    fn read_str_truncation() {
+        let _l = lock();
+        setup();
+
+        unsafe {
+            let mut buf = [0x7f as c_char; 4];
+            let mut n = 0;
+
+            // exactly fits, the terminator included
+            let status = bochscpu_mem_virt_read_cstr(CR3, GVA, buf.as_mut_ptr(), 4, &mut n);
+            assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+            assert_eq!((n, buf.map(|c| c as u8)), (3, *b"abc\0"));
+
+            // one element short
+            let status = bochscpu_mem_virt_read_cstr(CR3, GVA, buf.as_mut_ptr(), 3, &mut n);
+            assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+            assert_eq!(
+                crate::error::bochscpu_last_error(),
+                bochscpu_error_t::BOCHSCPU_ERROR_TRUNCATED
+            );
+            assert_eq!((n, &buf.map(|c| c as u8)[..3]), (2, &b"ab\0"[..]));
+
+            let mut wbuf = [0u16; 3];
+            let status = bochscpu_mem_virt_read_wstr(CR3, GVA + 4, wbuf.as_mut_ptr(), 3, &mut n);
+            assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+            assert_eq!((n, wbuf), (2, [b'h' as u16, b'i' as u16, 0]));
+        }
+    }
+}
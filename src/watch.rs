@@ -0,0 +1,550 @@
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use bochscpu::cpu::{Cpu, RunState};
+use bochscpu::hook::*;
+use bochscpu::{Address, PhyAddress};
+
+use crate::error::{bochscpu_error_t, bochscpu_status_t, fail, guard, guard_or};
+use crate::hook::bochscpu_hook_mem_t::{self, *};
+
+pub const BOCHSCPU_WATCH_READ: u32 = 1 << 0;
+pub const BOCHSCPU_WATCH_WRITE: u32 = 1 << 1;
+pub const BOCHSCPU_WATCH_EXECUTE: u32 = 1 << 2;
+
+const WATCH_ACCESS: u32 = BOCHSCPU_WATCH_READ | BOCHSCPU_WATCH_WRITE | BOCHSCPU_WATCH_EXECUTE;
+
+/// Address space a watchpoint range is in
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u32)]
+pub enum bochscpu_watch_space_t {
+    BOCHSCPU_WATCH_GVA = 0,
+    BOCHSCPU_WATCH_GPA = 1,
+}
+
+/// Watchpoint hit callback
+///
+/// Called with the id of the cpu, the id of the watchpoint, the GVA and GPA
+/// accessed, the size of the access and its kind, one of the
+/// `BOCHSCPU_HOOK_MEM_*` constants. Execute hits report the instruction
+/// pointer as the GVA, a GPA of zero and a size of zero.
+#[allow(non_camel_case_types)]
+pub type bochscpu_watch_cb_t = Option<
+    extern "C" fn(
+        ctx: *mut c_void,
+        cpu: u32,
+        watch: u64,
+        gva: u64,
+        gpa: u64,
+        len: usize,
+        access: bochscpu_hook_mem_t,
+    ),
+>;
+
+impl bochscpu_watch_space_t {
+    fn from_raw(v: u32) -> Option<Self> {
+        use bochscpu_watch_space_t::*;
+
+        [BOCHSCPU_WATCH_GVA, BOCHSCPU_WATCH_GPA]
+            .into_iter()
+            .find(|&k| k as u32 == v)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Watch {
+    id: u64,
+    start: u64,
+    end: u64,
+    access: u32,
+    stop: bool,
+}
+
+/// Watchpoints in one address space, sorted by start
+#[derive(Default)]
+struct Ranges {
+    watches: Vec<Watch>,
+    // longest range, bounds how far back an overlapping range can start
+    max_len: u64,
+}
+
+impl Ranges {
+    fn new<'a>(watches: impl Iterator<Item = &'a Watch>) -> Self {
+        let mut watches: Vec<Watch> = watches.copied().collect();
+        watches.sort_by_key(|w| w.start);
+
+        let max_len = watches.iter().map(|w| w.end - w.start).max().unwrap_or(0);
+
+        Ranges { watches, max_len }
+    }
+
+    /// Watchpoints overlapping the `len` bytes at `addr`
+    fn overlapping(&self, addr: u64, len: usize) -> impl Iterator<Item = &Watch> {
+        let last = addr.saturating_add(len.max(1) as u64 - 1);
+        let first = addr.saturating_sub(self.max_len);
+
+        let lo = self.watches.partition_point(|w| w.start < first);
+        let hi = self.watches.partition_point(|w| w.start <= last);
+
+        self.watches[lo..hi.max(lo)]
+            .iter()
+            .filter(move |w| addr < w.end)
+    }
+}
+
+/// Immutable view of the watchpoints handed to running cpus
+#[derive(Default)]
+struct Index {
+    gva: Ranges,
+    gpa: Ranges,
+    exec: Ranges,
+    cb: bochscpu_watch_cb_t,
+    ctx: usize,
+}
+
+struct Watches {
+    watches: BTreeMap<u64, (bochscpu_watch_space_t, Watch)>,
+    next_id: u64,
+    cb: bochscpu_watch_cb_t,
+    ctx: usize,
+    index: Option<Arc<Index>>,
+}
+
+impl Watches {
+    /// Drop the index after a change, it is rebuilt on next use
+    fn changed(&mut self) {
+        self.index = None;
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+
+    fn index(&mut self) -> Arc<Index> {
+        use bochscpu_watch_space_t::*;
+
+        if let Some(index) = &self.index {
+            return index.clone();
+        }
+
+        let watches = &self.watches;
+        let pick = |space, want: fn(u32) -> bool| {
+            Ranges::new(
+                watches
+                    .values()
+                    .filter(move |(s, w)| *s == space && want(w.access))
+                    .map(|(_, w)| w),
+            )
+        };
+
+        // a watchpoint may watch both data accesses and execution
+        let index = Arc::new(Index {
+            gva: pick(BOCHSCPU_WATCH_GVA, |a| a & !BOCHSCPU_WATCH_EXECUTE != 0),
+            gpa: pick(BOCHSCPU_WATCH_GPA, |_| true),
+            exec: pick(BOCHSCPU_WATCH_GVA, |a| a & BOCHSCPU_WATCH_EXECUTE != 0),
+            cb: self.cb,
+            ctx: self.ctx,
+        });
+
+        self.index = Some(index.clone());
+        index
+    }
+}
+
+static WATCHES: Mutex<Watches> = Mutex::new(Watches {
+    watches: BTreeMap::new(),
+    next_id: 0,
+    cb: None,
+    ctx: 0,
+    index: None,
+});
+
+fn watches() -> MutexGuard<'static, Watches> {
+    WATCHES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// bumped whenever WATCHES changes, so running cpus only take the lock to
+// pick up a new index
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Whether any watchpoint watches data accesses
+pub(crate) fn enabled() -> bool {
+    let index = watches().index();
+
+    !index.gva.watches.is_empty() || !index.gpa.watches.is_empty()
+}
+
+/// Whether any watchpoint watches instruction execution
+pub(crate) fn exec_enabled() -> bool {
+    !watches().index().exec.watches.is_empty()
+}
+
+/// Copy of the watchpoint index held by a running cpu
+#[derive(Default)]
+struct Snapshot {
+    index: Arc<Index>,
+    generation: u64,
+}
+
+impl Snapshot {
+    fn get(&mut self) -> Arc<Index> {
+        let generation = GENERATION.load(Ordering::Acquire);
+
+        if generation != self.generation {
+            self.index = watches().index();
+            self.generation = generation;
+        }
+
+        self.index.clone()
+    }
+}
+
+/// Report every watchpoint in `ranges` matching an access
+///
+/// The index is reference counted and no lock is held while calling out,
+/// so the callback may add or remove watchpoints.
+fn hit(
+    index: &Index,
+    ranges: &Ranges,
+    id: u32,
+    (addr, gva, gpa): (u64, u64, u64),
+    len: usize,
+    access: bochscpu_hook_mem_t,
+) {
+    let mask = match access {
+        BOCHSCPU_HOOK_MEM_READ => BOCHSCPU_WATCH_READ,
+        BOCHSCPU_HOOK_MEM_WRITE => BOCHSCPU_WATCH_WRITE,
+        BOCHSCPU_HOOK_MEM_RW => BOCHSCPU_WATCH_READ | BOCHSCPU_WATCH_WRITE,
+        BOCHSCPU_HOOK_MEM_EXECUTE => BOCHSCPU_WATCH_EXECUTE,
+    };
+
+    for w in ranges
+        .overlapping(addr, len)
+        .filter(|w| w.access & mask != 0)
+    {
+        if let Some(cb) = index.cb {
+            cb(index.ctx as _, id, w.id, gva, gpa, len, access);
+        }
+
+        if w.stop {
+            unsafe { Cpu::from(id).set_run_state(RunState::Stop) };
+        }
+    }
+}
+
+/// Evaluates watchpoints against guest data accesses
+#[derive(Default)]
+pub(crate) struct WatchHooks(Snapshot);
+
+impl Hooks for WatchHooks {
+    fn lin_access(
+        &mut self,
+        id: u32,
+        vaddr: Address,
+        paddr: Address,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        // fetches are checked against the instruction pointer instead
+        if matches!(rw, MemAccess::Execute) {
+            return;
+        }
+
+        let index = self.0.get();
+
+        hit(
+            &index,
+            &index.gva,
+            id,
+            (vaddr, vaddr, paddr),
+            len,
+            rw.into(),
+        );
+        hit(
+            &index,
+            &index.gpa,
+            id,
+            (paddr, vaddr, paddr),
+            len,
+            rw.into(),
+        );
+    }
+
+    fn phy_access(
+        &mut self,
+        id: u32,
+        paddr: PhyAddress,
+        len: usize,
+        _memty: MemType,
+        rw: MemAccess,
+    ) {
+        if matches!(rw, MemAccess::Execute) {
+            return;
+        }
+
+        let index = self.0.get();
+
+        hit(&index, &index.gpa, id, (paddr, 0, paddr), len, rw.into());
+    }
+}
+
+/// Evaluates execute watchpoints before each instruction
+#[derive(Default)]
+pub(crate) struct ExecWatchHooks(Snapshot);
+
+impl Hooks for ExecWatchHooks {
+    fn before_execution(&mut self, id: u32, _ins: *mut c_void) {
+        let index = self.0.get();
+        let rip = unsafe { Cpu::from(id).rip() };
+
+        hit(
+            &index,
+            &index.exec,
+            id,
+            (rip, rip, 0),
+            0,
+            BOCHSCPU_HOOK_MEM_EXECUTE,
+        );
+    }
+}
+
+/// Add a watchpoint
+///
+/// Watches the `len` bytes at `addr` in `space` for the accesses in
+/// `access`, a combination of `BOCHSCPU_WATCH_READ`, `BOCHSCPU_WATCH_WRITE`
+/// and `BOCHSCPU_WATCH_EXECUTE`. Execute watchpoints are matched against the
+/// instruction pointer before each instruction, and are only supported for
+/// `BOCHSCPU_WATCH_GVA`.
+///
+/// When a guest access overlaps the range the callback installed with
+/// `bochscpu_watch_callback()` is called, and if `stop` is set the cpu is
+/// stopped as with `bochscpu_cpu_stop()`. Accesses made through the
+/// `bochscpu_mem_*` functions do not trigger watchpoints.
+///
+/// `BOCHSCPU_WATCH_GPA` watchpoints also match accesses the core makes by
+/// physical address, such as page walks, which are reported with a GVA of
+/// zero.
+///
+/// A running cpu only checks data accesses if a read or write watchpoint
+/// existed when `bochscpu_cpu_run()` was called, and only checks execution
+/// if an execute watchpoint did. Otherwise watchpoints added by the
+/// callback take effect the next time the cpu is run.
+///
+/// The id of the new watchpoint is written to `id`.
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if the range is empty, or `space` or `access` is
+/// invalid.
+///
+/// # Safety
+///
+/// `id` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bochscpu_watch_add(
+    space: u32,
+    addr: u64,
+    len: u64,
+    access: u32,
+    stop: bool,
+    id: *mut u64,
+) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || unsafe {
+        use bochscpu_error_t::*;
+
+        let Some(space) = bochscpu_watch_space_t::from_raw(space) else {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("invalid watchpoint space {}", space),
+            );
+        };
+
+        if access == 0 || access & !WATCH_ACCESS != 0 {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("invalid watchpoint access {:#x}", access),
+            );
+        }
+
+        if space == bochscpu_watch_space_t::BOCHSCPU_WATCH_GPA
+            && access & BOCHSCPU_WATCH_EXECUTE != 0
+        {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                "execute watchpoints must be in the gva space",
+            );
+        }
+
+        let Some(end) = addr.checked_add(len).filter(|_| len != 0) else {
+            return fail(
+                BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!(
+                    "watchpoint {:#x} length {:#x} is not a valid range",
+                    addr, len
+                ),
+            );
+        };
+
+        let mut w = watches();
+        let next = w.next_id;
+        w.next_id += 1;
+        w.watches.insert(
+            next,
+            (
+                space,
+                Watch {
+                    id: next,
+                    start: addr,
+                    end,
+                    access,
+                    stop,
+                },
+            ),
+        );
+        w.changed();
+
+        *id = next;
+
+        bochscpu_status_t::BOCHSCPU_STATUS_OK
+    })
+}
+
+/// Remove a watchpoint
+///
+/// # Returns
+///
+/// `BOCHSCPU_STATUS_ERROR` if no watchpoint has the id `id`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_watch_remove(id: u64) -> bochscpu_status_t {
+    guard_or(bochscpu_status_t::BOCHSCPU_STATUS_PANIC, || {
+        let mut w = watches();
+
+        match w.watches.remove(&id) {
+            Some(_) => {
+                w.changed();
+                bochscpu_status_t::BOCHSCPU_STATUS_OK
+            }
+            None => fail(
+                bochscpu_error_t::BOCHSCPU_ERROR_INVALID_ARGUMENT,
+                &format!("no watchpoint with id {}", id),
+            ),
+        }
+    })
+}
+
+/// Remove every watchpoint
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_watch_clear() -> bochscpu_status_t {
+    guard(|| {
+        let mut w = watches();
+
+        w.watches.clear();
+        w.changed();
+    })
+}
+
+/// Set the watchpoint hit callback
+///
+/// `cb` is called with `ctx` each time a watchpoint is hit. Pass NULL to
+/// remove the callback, watchpoints with `stop` set still stop the cpu.
+#[unsafe(no_mangle)]
+pub extern "C" fn bochscpu_watch_callback(
+    cb: bochscpu_watch_cb_t,
+    ctx: *mut c_void,
+) -> bochscpu_status_t {
+    guard(|| {
+        let mut w = watches();
+
+        w.cb = cb;
+        w.ctx = ctx as usize;
+        w.changed();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+    use crate::testutil::lock;
+
+    type Hit = (u64, u64, u64, usize, bochscpu_hook_mem_t);
+
+    extern "C" fn cb(
+        ctx: *mut c_void,
+        _cpu: u32,
+        watch: u64,
+        gva: u64,
+        gpa: u64,
+        len: usize,
+        access: bochscpu_hook_mem_t,
+    ) {
+        unsafe { (*(ctx as *mut Vec<Hit>)).push((watch, gva, gpa, len, access)) };
+    }
+
+    fn add(space: u32, addr: u64, len: u64, access: u32) -> u64 {
+        let mut id = 0;
+        let status = unsafe { bochscpu_watch_add(space, addr, len, access, false, &mut id) };
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+
+        id
+    }
+
+    #[test]
+    fn hits() {
+        let _l = lock();
+        let mut log: Vec<Hit> = Vec::new();
+        let id = 0x304;
+
+        bochscpu_watch_callback(Some(cb), &mut log as *mut Vec<Hit> as _);
+
+        let gva = bochscpu_watch_space_t::BOCHSCPU_WATCH_GVA as u32;
+        let gpa = bochscpu_watch_space_t::BOCHSCPU_WATCH_GPA as u32;
+
+        let long = add(gva, 0x1000, 0x1000, BOCHSCPU_WATCH_WRITE);
+        let short = add(gva, 0x1ff8, 8, BOCHSCPU_WATCH_READ | BOCHSCPU_WATCH_WRITE);
+        let phys = add(gpa, 0xd0_0000, 0x10, BOCHSCPU_WATCH_READ);
+        let exec = add(gva, 0x4000, 1, BOCHSCPU_WATCH_EXECUTE);
+
+        let mut status = unsafe { bochscpu_watch_add(2, 0, 1, BOCHSCPU_WATCH_READ, false, &mut 0) };
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_ERROR);
+
+        assert!(enabled() && exec_enabled());
+
+        let mut hooks = WatchHooks::default();
+        let mut exec_hooks = ExecWatchHooks::default();
+
+        // only the part of the access inside a range matters
+        hooks.lin_access(id, 0xffc, 0xd0_0000, 8, MemType::Wb, MemAccess::Write);
+        hooks.lin_access(id, 0x1ffc, 0x10, 8, MemType::Wb, MemAccess::Read);
+        hooks.lin_access(id, 0x2000, 0x10, 8, MemType::Wb, MemAccess::Write);
+        hooks.phy_access(id, 0xd0_000f, 4, MemType::Wb, MemAccess::Read);
+        assert_eq!(
+            log,
+            [
+                (long, 0xffc, 0xd0_0000, 8, BOCHSCPU_HOOK_MEM_WRITE),
+                (short, 0x1ffc, 0x10, 8, BOCHSCPU_HOOK_MEM_READ),
+                (phys, 0, 0xd0_000f, 4, BOCHSCPU_HOOK_MEM_READ),
+            ]
+        );
+        log.clear();
+
+        unsafe {
+            Cpu::from(id).set_rip(0x4000);
+            exec_hooks.before_execution(id, ptr::null_mut());
+        }
+        assert_eq!(log, [(exec, 0x4000, 0, 0, BOCHSCPU_HOOK_MEM_EXECUTE)]);
+        log.clear();
+
+        // removals are picked up by running cpus
+        status = bochscpu_watch_remove(long);
+        assert_eq!(status, bochscpu_status_t::BOCHSCPU_STATUS_OK);
+        hooks.lin_access(id, 0x1000, 0x10, 8, MemType::Wb, MemAccess::Write);
+        assert!(log.is_empty());
+
+        bochscpu_watch_clear();
+        bochscpu_watch_callback(None, ptr::null_mut());
+        assert!(!enabled() && !exec_enabled());
+    }
+}